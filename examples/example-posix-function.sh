@@ -0,0 +1,32 @@
+#!/usr/bin/env sh
+
+# Parses the arguments of a function instead of the script.
+# Posix shells have no `local`, so the variables of the function are saved and
+# restored by `__argparse_restore_deploy`. The generated code calls it when it
+# returns early, on success the function calls it itself before returning.
+
+DEPLOY_CONFIG='
+function "deploy"
+description "deploy a target"
+
+opt "env" short="e" long="env" default="staging" description="the environment to deploy to"
+flag "dry_run" short="n" long="dry-run" description="only print what would be done"
+
+arg "target" value-name="TARGET" description="what to deploy"
+'
+
+deploy() {
+  eval "$(printf '%s' "$DEPLOY_CONFIG" | target/debug/clap-sh posix -- "$@")"
+
+  if [ -n "$dry_run" ]; then
+    printf 'would deploy %s to %s\n' "$target" "$env"
+  else
+    printf 'deploying %s to %s\n' "$target" "$env"
+  fi
+
+  __argparse_restore_deploy
+}
+
+env=local
+deploy -n --env prod "${1:-web}" || exit
+printf 'env is still %s\n' "$env"
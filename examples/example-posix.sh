@@ -9,6 +9,7 @@ version "6.6.6"
 handler "main"
 
 infer-subcommands true
+declare-all true
 // always-call-handler true
 
 opt "foo" short="f" long="foo" value-name="FOOS" description="how much foo"
@@ -16,7 +17,7 @@ opt "qux" short="q" long="qux" value-name="QUX" repeated=true description="which
 
 opt "name" short="n" long="name" default="world" description="the name to greet"
 
-flag "debug" short="d" long="debug" description="debug the application"
+flag "debug" short="d" long="debug" global=true description="debug the application"
 
 // arg "input_files" value-name="FILE" description="input files" count="+"
 // arg "output_file" value-name="FILE" description="output file"
@@ -46,19 +47,14 @@ subcommand "boo" {
 }
 '
 
-name=
-qux=
-key=
-value=
-
 main() {
   printf 'hello %s\n' "$name!"
 
-  if [ -n "${debug+x}" ]; then
+  if [ -n "$debug" ]; then
     echo "debug is on!"
   fi
 
-  if [ -z "${foo+x}" ]; then
+  if [ -z "$foo" ]; then
     echo "foo is not set!"
   else
     printf 'foo is %s\n' "$foo"
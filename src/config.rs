@@ -1,99 +1,498 @@
-#[derive(knus::Decode, Debug, Clone)]
+use std::cell::Cell;
+use std::path::Path;
+use std::str::FromStr;
+use clap::ColorChoice;
+use miette::IntoDiagnostic;
+use knus::ast::SpannedNode;
+use knus::span::Span;
+use crate::fragments::{self, string_argument};
+
+/// The byte offset of a node in the config, used to keep the declaration order
+/// of opts, flags and args which are collected in separate lists.
+#[derive(Debug, Copy, Clone, Default)]
+struct Position(usize);
+
+thread_local! {
+    static NEXT_POSITION: Cell<usize> = const { Cell::new(0) };
+}
+
+impl Position {
+    /// The position of an item deserialized with serde, which completes
+    /// the items of a config in document order.
+    fn next() -> Position {
+        NEXT_POSITION.with(|next| {
+            let position = next.get();
+            next.set(position + 1);
+            Position(position)
+        })
+    }
+}
+
+impl knus::traits::DecodeSpan<Span> for Position {
+    fn decode_span(span: &Span, _: &mut knus::decode::Context<Span>) -> Self {
+        Position(span.0)
+    }
+}
+
+/// An option that takes a value.
+#[derive(knus::Decode, serde::Deserialize, schemars::JsonSchema, Debug, Clone)]
+#[knus(span_type=Span)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
 struct CfgOpt {
+    #[knus(span)]
+    #[serde(skip, default = "Position::next")]
+    position: Position,
+    /// The name of the opt.
     #[knus(argument)]
     name: String,
+    /// The short name, a single character.
     #[knus(property)]
     short: Option<String>,
+    /// The long name.
     #[knus(property)]
     long: Option<String>,
+    /// The name of the value in help and usage, the upper-cased name by default.
     #[knus(property)]
     value_name: Option<String>,
+    /// The value used when the opt is not given.
     #[knus(property)]
     default: Option<String>,
+    /// Whether the opt can be given several times, its values are collected in a list.
     #[knus(property)]
     repeated: Option<bool>,
+    /// Whether the opt is also accepted by all subcommands below.
+    #[knus(property)]
+    global: Option<bool>,
+    /// The help text of the opt.
     #[knus(property)]
     description: Option<String>,
+    /// The help text for `--help`, the description is used for `-h`.
+    #[knus(property)]
+    long_description: Option<String>,
+    /// The heading it is listed under in help.
+    #[knus(property)]
+    help_heading: Option<String>,
+    /// The position in help, items without one are listed in declaration order.
+    #[knus(property)]
+    display_order: Option<usize>,
+    /// Whether the help text starts on the line after the name.
+    #[knus(property)]
+    next_line_help: Option<bool>,
+    /// Whether it is hidden from help.
+    #[knus(property)]
+    hide: Option<bool>,
+    /// The variable the value is stored in, derived from the name by default.
+    #[knus(property)]
+    var: Option<String>,
+    /// Hidden alternative long names.
+    #[knus(children(name = "alias"), unwrap(argument))]
+    #[serde(default)]
+    aliases: Vec<String>,
+    /// Alternative long names that are listed in help.
+    #[knus(children(name = "visible-alias"), unwrap(argument))]
+    #[serde(default)]
+    visible_aliases: Vec<String>,
+    /// Hidden alternative short names.
+    #[knus(children(name = "short-alias"), unwrap(argument))]
+    #[serde(default)]
+    short_aliases: Vec<String>,
 }
 
 
-#[derive(knus::Decode, Debug, Clone)]
+/// A flag that takes no value.
+#[derive(knus::Decode, serde::Deserialize, schemars::JsonSchema, Debug, Clone)]
+#[knus(span_type=Span)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
 struct CfgFlag {
+    #[knus(span)]
+    #[serde(skip, default = "Position::next")]
+    position: Position,
+    /// The name of the flag.
     #[knus(argument)]
     name: String,
+    /// The short name, a single character.
     #[knus(property)]
     short: Option<String>,
+    /// The long name.
     #[knus(property)]
     long: Option<String>,
+    /// Whether the flag is also accepted by all subcommands below.
+    #[knus(property)]
+    global: Option<bool>,
+    /// The help text of the flag.
     #[knus(property)]
     description: Option<String>,
+    /// The help text for `--help`, the description is used for `-h`.
+    #[knus(property)]
+    long_description: Option<String>,
+    /// The heading it is listed under in help.
+    #[knus(property)]
+    help_heading: Option<String>,
+    /// The position in help, items without one are listed in declaration order.
+    #[knus(property)]
+    display_order: Option<usize>,
+    /// Whether the help text starts on the line after the name.
+    #[knus(property)]
+    next_line_help: Option<bool>,
+    /// Whether it is hidden from help.
+    #[knus(property)]
+    hide: Option<bool>,
+    /// The variable the value is stored in, derived from the name by default.
+    #[knus(property)]
+    var: Option<String>,
+    /// Hidden alternative long names.
+    #[knus(children(name = "alias"), unwrap(argument))]
+    #[serde(default)]
+    aliases: Vec<String>,
+    /// Alternative long names that are listed in help.
+    #[knus(children(name = "visible-alias"), unwrap(argument))]
+    #[serde(default)]
+    visible_aliases: Vec<String>,
+    /// Hidden alternative short names.
+    #[knus(children(name = "short-alias"), unwrap(argument))]
+    #[serde(default)]
+    short_aliases: Vec<String>,
 }
 
-#[derive(knus::Decode, Debug, Clone)]
+/// A positional argument.
+#[derive(knus::Decode, serde::Deserialize, schemars::JsonSchema, Debug, Clone)]
+#[knus(span_type=Span)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
 struct CfgArg {
+    #[knus(span)]
+    #[serde(skip, default = "Position::next")]
+    position: Position,
+    /// The name of the arg.
     #[knus(argument)]
     name: String,
+    /// The name of the value in help and usage, the upper-cased name by default.
     #[knus(property)]
     value_name: Option<String>,
+    /// How many values the arg takes, exactly one (`1`), at least one (`+`) or any number (`*`).
     #[knus(property)]
+    #[schemars(extend("enum" = ["1", "+", "*", null]))]
     count: Option<String>,
+    /// Whether the arg only takes the values after `--`.
+    #[knus(property)]
+    last: Option<bool>,
+    /// Whether the arg takes all remaining arguments verbatim, including unknown opts and flags.
+    #[knus(property)]
+    passthrough: Option<bool>,
+    /// The help text of the arg.
     #[knus(property)]
     description: Option<String>,
+    /// The help text for `--help`, the description is used for `-h`.
+    #[knus(property)]
+    long_description: Option<String>,
+    /// The heading it is listed under in help.
+    #[knus(property)]
+    help_heading: Option<String>,
+    /// The position in help, items without one are listed in declaration order.
+    #[knus(property)]
+    display_order: Option<usize>,
+    /// Whether the help text starts on the line after the name.
+    #[knus(property)]
+    next_line_help: Option<bool>,
+    /// Whether it is hidden from help.
+    #[knus(property)]
+    hide: Option<bool>,
+    /// The variable the value is stored in, derived from the name by default.
+    #[knus(property)]
+    var: Option<String>,
 }
 
 
-#[derive(knus::Decode, Debug, Clone)]
+/// An example command line.
+#[derive(knus::Decode, serde::Deserialize, schemars::JsonSchema, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct CfgExample {
+    /// The example command line.
+    #[knus(argument)]
+    command: String,
+    /// What the example does.
+    #[knus(property)]
+    description: Option<String>,
+}
+
+#[derive(knus::Decode, serde::Deserialize, schemars::JsonSchema, Debug, Clone, Default)]
+#[serde(transparent)]
+struct CfgExamples {
+    #[knus(children(name = "example"))]
+    examples: Vec<CfgExample>,
+}
+
+
+/// A subcommand.
+#[derive(knus::Decode, serde::Deserialize, schemars::JsonSchema, Debug, Clone)]
+#[knus(span_type=Span)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
 struct CfgCommand {
+    /// The name of the subcommand.
     #[knus(argument)]
     name: String,
+    /// A short flag that selects the subcommand, as in `-S`.
     #[knus(child, unwrap(argument))]
     short_flag: Option<String>,
+    /// A long flag that selects the subcommand, as in `--sync`.
     #[knus(child, unwrap(argument))]
     long_flag: Option<String>,
+    /// Hidden alternative names.
+    #[knus(children(name = "alias"), unwrap(argument))]
+    #[serde(default)]
+    aliases: Vec<String>,
+    /// Alternative names that are listed in help.
+    #[knus(children(name = "visible-alias"), unwrap(argument))]
+    #[serde(default)]
+    visible_aliases: Vec<String>,
+    /// Hidden alternative short flags.
+    #[knus(children(name = "short-alias"), unwrap(argument))]
+    #[serde(default)]
+    short_aliases: Vec<String>,
+    /// Whether a subcommand must be given.
     #[knus(child, unwrap(argument))]
     require_subcommand: Option<bool>,
+    /// The position of the subcommand in help.
+    #[knus(child, unwrap(argument))]
+    display_order: Option<usize>,
+    /// Whether the subcommand is hidden from help.
+    #[knus(child, unwrap(argument))]
+    hide: Option<bool>,
+    /// The help text of the subcommand.
     #[knus(child, unwrap(argument))]
     description: Option<String>,
+    /// The help text for `--help`, the description is used for `-h`.
+    #[knus(child, unwrap(argument))]
+    long_description: Option<String>,
+    /// Text shown before the help.
+    #[knus(child, unwrap(argument))]
+    before_help: Option<String>,
+    /// Text shown after the help.
+    #[knus(child, unwrap(argument))]
+    after_help: Option<String>,
+    /// Example command lines shown after the help.
+    #[knus(child)]
+    examples: Option<CfgExamples>,
+    /// Whether help texts start on the line after the names.
+    #[knus(child, unwrap(argument))]
+    next_line_help: Option<bool>,
+    /// A clap help template that replaces the layout of the help.
+    #[knus(child, unwrap(argument))]
+    help_template: Option<String>,
+    /// The usage line, generated from the opts, flags and args by default.
+    #[knus(child, unwrap(argument))]
+    usage: Option<String>,
+    /// The function called when the command is selected.
     #[knus(child, unwrap(argument))]
     handler: Option<String>,
+    /// The args whose values are passed to the handler as arguments.
+    #[knus(child, unwrap(arguments))]
+    handler_args: Option<Vec<String>>,
+    /// Whether the handler is called with `exec`, which replaces the shell. The handler must be an executable.
+    #[knus(child, unwrap(argument))]
+    exec_handler: Option<bool>,
+    /// A function called before the handler.
+    #[knus(child, unwrap(argument))]
+    before_handler: Option<String>,
+    /// A function called after the handler, also if it fails.
+    #[knus(child, unwrap(argument))]
+    after_handler: Option<String>,
+    /// A function called with the status and the name of a failing handler or hook.
+    #[knus(child, unwrap(argument))]
+    error_handler: Option<String>,
+    /// Whether the handler is also called when a subcommand is selected, before the handler of the subcommand.
     #[knus(child, unwrap(argument))]
     always_call_handler: Option<bool>,
+    /// The subcommands.
     #[knus(children(name = "subcommand"))]
+    #[serde(default)]
     subcommands: Vec<CfgCommand>,
+    /// Options that take a value.
     #[knus(children(name = "opt"))]
+    #[serde(default)]
     opts: Vec<CfgOpt>,
+    /// Flags that take no value, their variable counts how often they are given.
     #[knus(children(name = "flag"))]
+    #[serde(default)]
     flags: Vec<CfgFlag>,
+    /// Positional arguments.
     #[knus(children(name = "arg"))]
+    #[serde(default)]
     args: Vec<CfgArg>,
 }
 
 
-#[derive(knus::Decode, Debug, Clone)]
+/// The exit status of the script after help, version and usage errors.
+#[derive(knus::Decode, serde::Deserialize, schemars::JsonSchema, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct CfgExitCodes {
+    /// The exit status after showing help.
+    #[knus(child, unwrap(argument))]
+    help: Option<i32>,
+    /// The exit status after showing the version.
+    #[knus(child, unwrap(argument))]
+    version: Option<i32>,
+    /// The exit status after usage errors.
+    #[knus(child, unwrap(argument))]
+    usage: Option<i32>,
+}
+
+
+/// The settings and the top level command of an app.
+#[derive(knus::Decode, serde::Deserialize, schemars::JsonSchema, Debug, Clone, Default)]
+#[knus(span_type=Span)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+#[schemars(title = "clap-sh config")]
 struct CfgApp {
+    /// The name of the app, used in help and errors.
     #[knus(child, unwrap(argument))]
     name: Option<String>,
+    /// The version shown by `--version`.
     #[knus(child, unwrap(argument))]
     version: Option<String>,
+    /// The author shown in help.
+    #[knus(child, unwrap(argument))]
+    author: Option<String>,
+    /// The homepage of the app.
+    #[knus(child, unwrap(argument))]
+    homepage: Option<String>,
+    /// Where to report bugs.
+    #[knus(child, unwrap(argument))]
+    bug_report_url: Option<String>,
+    /// Whether the subcommand is selected by the name the script is called as, for symlinks to the script.
+    #[knus(child, unwrap(argument))]
+    multicall: Option<bool>,
+    /// Whether unambiguous prefixes of subcommands are accepted.
     #[knus(child, unwrap(argument))]
     infer_subcommands: Option<bool>,
+    /// Whether opts and flags can be given again, the last value wins.
     #[knus(child, unwrap(argument))]
     args_override_self: Option<bool>,
+    /// A prefix for all derived variable names.
+    #[knus(child, unwrap(argument))]
+    var_prefix: Option<String>,
+    /// The case of derived variable names.
+    #[knus(child, unwrap(argument))]
+    #[schemars(extend("enum" = ["upper", "lower", "keep", null]))]
+    var_case: Option<String>,
+    /// Whether derived variable names start with the names of their subcommands.
+    #[knus(child, unwrap(argument))]
+    namespace_subcommands: Option<bool>,
+    /// Whether the variables of all subcommands are declared, not only those of the selected ones.
+    #[knus(child, unwrap(argument))]
+    declare_all: Option<bool>,
+    /// How variables without a value are represented.
+    #[knus(child, unwrap(argument))]
+    #[schemars(extend("enum" = ["marker", "unset", "empty", "sentinel", null]))]
+    unset_style: Option<String>,
+    /// The suffix of the marker variables of the `marker` unset style, `_not_set` by default.
+    #[knus(child, unwrap(argument))]
+    unset_suffix: Option<String>,
+    /// The value of unset variables in the `sentinel` unset style.
+    #[knus(child, unwrap(argument))]
+    unset_sentinel: Option<String>,
+    /// How lists are represented in posix shells, which have no arrays.
+    #[knus(child, unwrap(argument))]
+    #[schemars(extend("enum" = ["quoted", "set", "numbered", "lines", null]))]
+    posix_list_style: Option<String>,
+    /// The shell function whose arguments are parsed, its variables are local to it.
+    #[knus(child, unwrap(argument))]
+    function: Option<String>,
+    /// What the generated code is wrapped in.
+    #[knus(child, unwrap(argument))]
+    #[schemars(extend("enum" = ["none", "block", "function", null]))]
+    wrap: Option<String>,
+    /// The name of the function of the `function` wrap, `__argparse_main` by default.
+    #[knus(child, unwrap(argument))]
+    wrap_function: Option<String>,
+    /// The variable that holds the selected subcommands, separated by spaces.
+    #[knus(child, unwrap(argument))]
+    subcommand_var: Option<String>,
+    /// The list variable that holds the selected subcommands.
+    #[knus(child, unwrap(argument))]
+    subcommand_path_var: Option<String>,
+    /// A function called instead of printing parse errors, the script only exits if it fails.
+    #[knus(child, unwrap(argument))]
+    on_parse_error: Option<String>,
+    /// The exit status after help, version and usage errors.
+    #[knus(child)]
+    exit_codes: Option<CfgExitCodes>,
+    /// When output is styled.
+    #[knus(child, unwrap(argument))]
+    #[schemars(extend("enum" = ["auto", "always", "never", null]))]
+    color: Option<String>,
+    /// Whether a `--color` opt is added.
+    #[knus(child, unwrap(argument))]
+    color_opt: Option<bool>,
+    /// The prefix of executables that are accepted as subcommands, as in `git-<name>`.
+    #[knus(child, unwrap(argument))]
+    external_subcommands: Option<String>,
+    /// Directories searched for external subcommands before `PATH`.
+    #[knus(children(name = "plugin-dir"), unwrap(argument))]
+    #[serde(default)]
+    plugin_dirs: Vec<String>,
+    /// The help text of the app.
     #[knus(child, unwrap(argument))]
     description: Option<String>,
+    /// The help text for `--help`, the description is used for `-h`.
+    #[knus(child, unwrap(argument))]
+    long_description: Option<String>,
+    /// Text shown before the help.
+    #[knus(child, unwrap(argument))]
+    before_help: Option<String>,
+    /// Text shown after the help.
+    #[knus(child, unwrap(argument))]
+    after_help: Option<String>,
+    /// Example command lines shown after the help.
+    #[knus(child)]
+    examples: Option<CfgExamples>,
+    /// Whether help texts start on the line after the names.
+    #[knus(child, unwrap(argument))]
+    next_line_help: Option<bool>,
+    /// A clap help template that replaces the layout of the help.
+    #[knus(child, unwrap(argument))]
+    help_template: Option<String>,
+    /// The usage line, generated from the opts, flags and args by default.
+    #[knus(child, unwrap(argument))]
+    usage: Option<String>,
+    /// The function called when no subcommand is selected.
     #[knus(child, unwrap(argument))]
     handler: Option<String>,
+    /// The args whose values are passed to the handler as arguments.
+    #[knus(child, unwrap(arguments))]
+    handler_args: Option<Vec<String>>,
+    /// Whether the handler is called with `exec`, which replaces the shell. The handler must be an executable.
+    #[knus(child, unwrap(argument))]
+    exec_handler: Option<bool>,
+    /// A function called before the handlers.
+    #[knus(child, unwrap(argument))]
+    before_handler: Option<String>,
+    /// A function called after the handlers, also if one fails.
+    #[knus(child, unwrap(argument))]
+    after_handler: Option<String>,
+    /// A function called with the status and the name of a failing handler or hook.
+    #[knus(child, unwrap(argument))]
+    error_handler: Option<String>,
+    /// Whether the handler is also called when a subcommand is selected, before the handler of the subcommand.
     #[knus(child, unwrap(argument))]
     always_call_handler: Option<bool>,
+    /// Whether a subcommand must be given.
     #[knus(child, unwrap(argument))]
     require_subcommand: Option<bool>,
+    /// The subcommands.
     #[knus(children(name = "subcommand"))]
+    #[serde(default)]
     subcommands: Vec<CfgCommand>,
+    /// Options that take a value.
     #[knus(children(name = "opt"))]
+    #[serde(default)]
     opts: Vec<CfgOpt>,
+    /// Flags that take no value, their variable counts how often they are given.
     #[knus(children(name = "flag"))]
+    #[serde(default)]
     flags: Vec<CfgFlag>,
+    /// Positional arguments.
     #[knus(children(name = "arg"))]
+    #[serde(default)]
     args: Vec<CfgArg>,
 }
 
@@ -101,8 +500,30 @@ struct CfgApp {
 pub struct App {
     pub name: Option<String>,
     pub version: Option<String>,
+    pub author: Option<String>,
+    pub homepage: Option<String>,
+    pub bug_report_url: Option<String>,
+    pub multicall: bool,
     pub infer_subcommands: bool,
     pub args_override_self: bool,
+    pub declare_all: bool,
+    pub unset_style: UnsetStyle,
+    pub posix_list_style: ListStyle,
+    /// The shell function whose arguments are parsed, if any. Its variables are
+    /// local, posix functions call `__argparse_restore_<function>` to restore them.
+    pub function: Option<String>,
+    pub wrap: Wrap,
+    pub subcommand_var: String,
+    pub subcommand_path_var: String,
+    pub hooks: Hooks,
+    /// Called instead of printing parse errors, the script only exits if it
+    /// returns a non-zero status, with that status.
+    pub on_parse_error: Option<String>,
+    pub exit_codes: ExitCodes,
+    pub color: ColorChoice,
+    pub color_opt: bool,
+    pub external_subcommands: Option<String>,
+    pub plugin_dirs: Vec<String>,
     pub command: Command,
 }
 
@@ -111,9 +532,23 @@ pub struct Command {
     pub name: String,
     pub short_flag: Option<char>,
     pub long_flag: Option<String>,
+    pub aliases: Aliases,
     pub require_subcommand: bool,
+    pub display_order: Option<usize>,
+    pub hide: bool,
     pub description: Option<String>,
+    pub long_description: Option<String>,
+    pub before_help: Option<String>,
+    pub after_help: Option<String>,
+    pub examples: Vec<Example>,
+    pub next_line_help: bool,
+    pub help_template: Option<String>,
+    pub usage: Option<String>,
     pub handler: Option<String>,
+    pub handler_args: Vec<String>,
+    /// The handler replaces the shell, so it must be an executable rather than a function.
+    pub exec_handler: bool,
+    pub hooks: Hooks,
     pub always_call_handler: bool,
     pub flags: Vec<Flag>,
     pub opts: Vec<Opt>,
@@ -121,20 +556,74 @@ pub struct Command {
     pub subcommands: Vec<Command>,
 }
 
+/// The exit codes used when printing help, version or an error.
+#[derive(Debug, Clone)]
+pub struct ExitCodes {
+    pub help: i32,
+    pub version: i32,
+    pub usage: i32,
+}
+
+/// Functions that are called around handlers.
+/// On the app level they wrap the whole handler chain,
+/// on the command level only the handler of that command.
+/// With hooks the handlers are called in `||` lists, so `set -e` is disabled inside them.
+#[derive(Debug, Clone, Default)]
+pub struct Hooks {
+    pub before: Option<String>,
+    pub after: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Aliases {
+    pub hidden: Vec<String>,
+    pub visible: Vec<String>,
+    pub short: Vec<char>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Example {
+    pub command: String,
+    pub description: Option<String>,
+}
+
+/// Help settings shared by flags, opts and args.
+#[derive(Debug, Clone)]
+pub struct ArgHelp {
+    pub long_description: Option<String>,
+    pub heading: Option<String>,
+    pub display_order: Option<usize>,
+    pub next_line_help: bool,
+    pub hide: bool,
+}
+
 #[derive(Debug, Clone)]
 pub struct Flag {
     pub name: String,
+    pub var: String,
+    /// Orders opts, flags and args as declared in the config.
+    pub position: usize,
     pub short: Option<char>,
     pub long: Option<String>,
+    pub aliases: Aliases,
+    pub global: bool,
     pub description: Option<String>,
+    pub help: ArgHelp,
 }
 
 #[derive(Debug, Clone)]
 pub struct Opt {
     pub name: String,
+    pub var: String,
+    /// Orders opts, flags and args as declared in the config.
+    pub position: usize,
     pub short: Option<char>,
     pub long: Option<String>,
+    pub aliases: Aliases,
+    pub global: bool,
     pub description: Option<String>,
+    pub help: ArgHelp,
     pub value_name: String,
     pub default: Option<String>,
     pub repeated: bool,
@@ -143,122 +632,748 @@ pub struct Opt {
 #[derive(Debug, Clone)]
 pub struct Arg {
     pub name: String,
+    pub var: String,
+    /// Orders opts, flags and args as declared in the config.
+    pub position: usize,
     pub description: Option<String>,
+    pub help: ArgHelp,
     pub value_name: String,
     pub count: Count,
+    pub passthrough: Passthrough,
 }
 
 
+/// Args that capture the remaining arguments verbatim.
+#[derive(Debug, Copy, Clone)]
+pub enum Passthrough {
+    No,
+    /// only the arguments after `--`
+    Last,
+    /// everything from the first value of the arg on, including unknown opts and flags
+    Trailing,
+}
+
 #[derive(Debug, Copy, Clone)]
 pub enum Count {
     One,
     AtLeastOne,
     Any,
-    Exactly(usize),
-    MinMax(usize, usize),
-}
-
-impl Into<Opt> for CfgOpt {
-    fn into(self) -> Opt {
-        Opt {
-            name: self.name.clone(),
-            short: short_flag(self.short),
-            long: self.long,
-            description: self.description,
-            value_name: self.value_name.unwrap_or(self.name.to_uppercase()),
-            default: self.default,
-            repeated: self.repeated.unwrap_or(false),
+}
+
+/// How a variable without a value is represented in the generated code.
+#[derive(Debug, Clone)]
+pub enum UnsetStyle {
+    /// `<name><suffix>='y'`
+    Marker(String),
+    /// `unset <name>`
+    Unset,
+    /// `<name>=''`
+    Empty,
+    /// `<name>='<sentinel>'`
+    Sentinel(String),
+}
+
+/// How a list is represented in the generated posix code, bash always uses arrays.
+#[derive(Debug, Clone)]
+pub enum ListStyle {
+    /// `<name>='<quoted words>'`, to be used with `eval`
+    Quoted,
+    /// `<name>='set -- <quoted words>'`, to be used with `eval`
+    Set,
+    /// `<name>_count=<n>` and `<name>_1` to `<name>_<n>`
+    Numbered,
+    /// every value followed by a newline, iterated with the generated
+    /// `__argparse_foreach <name> <function>`, values containing newlines are rejected.
+    /// An empty list is `''`, a list of one empty value is a single newline.
+    /// The helper keeps the list and the current value in the global variables
+    /// `__argparse_foreach_list` and `__argparse_foreach_item`
+    Lines,
+}
+
+/// What the generated code is wrapped in.
+#[derive(Debug, Clone)]
+pub enum Wrap {
+    None,
+    /// `{ ...; }`, so that nothing runs before the whole output is parsed
+    Block,
+    /// a function with the given name, which is defined but not called
+    Function(String),
+}
+
+#[derive(Debug, Copy, Clone)]
+enum VarCase {
+    Upper,
+    Lower,
+    Keep,
+}
+
+/// How variable names are derived from the names of opts, flags and args.
+#[derive(Debug, Clone)]
+struct VarNaming {
+    prefix: String,
+    case: VarCase,
+    namespace_subcommands: bool,
+    /// The suffix of the markers of unset variables, whose names are taken as well.
+    marker_suffix: Option<String>,
+}
+
+impl VarNaming {
+    fn var_name(&self, path: &[String], name: &str) -> String {
+        let name = if self.namespace_subcommands {
+            path.iter().map(|x| x.as_str()).chain([name]).collect::<Vec<_>>().join("_")
+        } else {
+            name.to_string()
+        };
+        let name = match self.case {
+            VarCase::Upper => name.to_uppercase(),
+            VarCase::Lower => name.to_lowercase(),
+            VarCase::Keep => name,
+        };
+        sanitize_var_name(format!("{}{}", self.prefix, name).as_str())
+    }
+
+    /// Fills in the variable names of all items in the command tree.
+    /// Items with an explicit `var` keep it as is (apart from sanitizing).
+    ///
+    /// A command sets its variables together with those of its parent commands,
+    /// so their names must be distinct. With `namespace_subcommands` every item
+    /// has a variable of its own, so the names of the whole tree must be distinct.
+    /// `taken` holds the variables in use with a description of their item.
+    fn apply(&self, command: &mut Command, path: &mut Vec<String>, taken: &mut Vec<(String, String)>) -> miette::Result<()> {
+        let inherited = taken.len();
+        for flag in &mut command.flags {
+            flag.var = self.resolve(path, &flag.name, &flag.var);
+            self.take(taken, &flag.var, &flag.name, path)?;
         }
+        for opt in &mut command.opts {
+            opt.var = self.resolve(path, &opt.name, &opt.var);
+            self.take(taken, &opt.var, &opt.name, path)?;
+        }
+        for arg in &mut command.args {
+            arg.var = self.resolve(path, &arg.name, &arg.var);
+            self.take(taken, &arg.var, &arg.name, path)?;
+        }
+        let own = taken.len();
+        for subcommand in &mut command.subcommands {
+            path.push(subcommand.name.clone());
+            self.apply(subcommand, path, taken)?;
+            path.pop();
+            if !self.namespace_subcommands {
+                taken.truncate(own);
+            }
+        }
+        if !self.namespace_subcommands {
+            taken.truncate(inherited);
+        }
+        Ok(())
+    }
+
+    /// Records the variable of an item and its unset marker,
+    /// failing if another item already uses one of their names.
+    fn take(&self, taken: &mut Vec<(String, String)>, var: &str, name: &str, path: &[String]) -> miette::Result<()> {
+        let item = if path.is_empty() { format!("'{name}'") } else { format!("'{name}' of subcommand '{}'", path.join(" ")) };
+        let mut names = vec![(var.to_string(), format!("the variable of {item}"))];
+        if let Some(suffix) = &self.marker_suffix {
+            names.push((format!("{var}{suffix}"), format!("the unset marker of {item}")));
+        }
+        for (var, item) in names {
+            if let Some((_, other)) = taken.iter().find(|(taken, _)| *taken == var) {
+                miette::bail!("{item} and {other} are both named '{var}'");
+            }
+            taken.push((var, item));
+        }
+        Ok(())
+    }
+
+    fn resolve(&self, path: &[String], name: &str, explicit: &str) -> String {
+        if explicit.is_empty() {
+            self.var_name(path, name)
+        } else {
+            sanitize_var_name(explicit)
+        }
+    }
+}
+
+/// Turns an arbitrary string into a valid shell identifier.
+fn sanitize_var_name(name: &str) -> String {
+    let mut sanitized: String = name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' })
+        .collect();
+    if sanitized.is_empty() || sanitized.starts_with(|c: char| c.is_ascii_digit()) {
+        sanitized.insert(0, '_');
     }
+    sanitized
 }
 
+impl TryFrom<CfgOpt> for Opt {
+    type Error = miette::Report;
 
-fn short_flag(name: Option<String>) -> Option<char> {
+    fn try_from(cfg: CfgOpt) -> miette::Result<Opt> {
+        Ok(Opt {
+            name: cfg.name.clone(),
+            position: cfg.position.0,
+            var: cfg.var.unwrap_or_default(),
+            short: short_flag(cfg.short)?,
+            long: cfg.long,
+            aliases: aliases(cfg.aliases, cfg.visible_aliases, cfg.short_aliases)?,
+            global: cfg.global.unwrap_or(false),
+            description: cfg.description,
+            help: ArgHelp {
+                long_description: cfg.long_description,
+                heading: cfg.help_heading,
+                display_order: cfg.display_order,
+                next_line_help: cfg.next_line_help.unwrap_or(false),
+                hide: cfg.hide.unwrap_or(false),
+            },
+            value_name: cfg.value_name.unwrap_or(cfg.name.to_uppercase()),
+            default: cfg.default,
+            repeated: cfg.repeated.unwrap_or(false),
+        })
+    }
+}
+
+
+fn short_flag(name: Option<String>) -> miette::Result<Option<char>> {
     match name {
         Some(s) => {
             if s.chars().count() == 1 {
-                s.chars().nth(0)
+                Ok(s.chars().next())
             } else {
-                panic!("invalid short name (must be exactly one character)")
+                miette::bail!("invalid short name '{s}' (must be exactly one character)")
             }
         }
-        _ => None,
+        _ => Ok(None),
     }
 }
 
-impl Into<Flag> for CfgFlag {
-    fn into(self) -> Flag {
-        Flag {
-            name: self.name.clone(),
-            short: short_flag(self.short),
-            long: self.long,
-            description: self.description,
+fn handler_args(names: Option<Vec<String>>, args: &[CfgArg]) -> miette::Result<Vec<String>> {
+    let names = names.unwrap_or_default();
+    for name in &names {
+        if !args.iter().any(|arg| &arg.name == name) {
+            miette::bail!("invalid handler-args (unknown arg '{name}')");
         }
     }
+    Ok(names)
+}
+
+fn aliases(hidden: Vec<String>, visible: Vec<String>, short: Vec<String>) -> miette::Result<Aliases> {
+    Ok(Aliases {
+        hidden,
+        visible,
+        short: short.into_iter().filter_map(|s| short_flag(Some(s)).transpose()).collect::<miette::Result<_>>()?,
+    })
+}
+
+fn uses_exec_handler(command: &Command) -> bool {
+    command.exec_handler || command.subcommands.iter().any(uses_exec_handler)
+}
+
+/// The name, short and long of an opt, flag or arg, as far as clap sees them.
+struct ArgNames<'a> {
+    name: &'a str,
+    short: Option<char>,
+    long: Option<&'a str>,
+}
+
+impl ArgNames<'_> {
+    fn collides(&self, other: &ArgNames) -> bool {
+        self.name == other.name
+            || self.short.is_some() && self.short == other.short
+            || self.long.is_some() && self.long == other.long
+    }
 }
 
-impl Into<Arg> for CfgArg {
-    fn into(self) -> Arg {
-        Arg {
-            name: self.name.clone(),
-            description: self.description,
-            value_name: self.value_name.unwrap_or(self.name.to_uppercase()),
-            count: match self.count {
+/// Global opts and flags are added to every subcommand below the command that
+/// declares them, so their names, shorts and longs can not be reused there.
+fn check_globals<'a>(command: &'a Command, globals: &mut Vec<ArgNames<'a>>) -> miette::Result<()> {
+    let inherited = globals.len();
+    let flags = command.flags.iter().map(|flag| (flag.global, ArgNames { name: &flag.name, short: flag.short, long: flag.long.as_deref() }));
+    let opts = command.opts.iter().map(|opt| (opt.global, ArgNames { name: &opt.name, short: opt.short, long: opt.long.as_deref() }));
+    let args = command.args.iter().map(|arg| (false, ArgNames { name: &arg.name, short: None, long: None }));
+    for (global, names) in flags.chain(opts).chain(args) {
+        if let Some(other) = globals[..inherited].iter().find(|other| other.collides(&names)) {
+            miette::bail!("'{}' of subcommand '{}' collides with the global '{}'", names.name, command.name, other.name);
+        }
+        if global {
+            globals.push(names);
+        }
+    }
+    for subcommand in &command.subcommands {
+        check_globals(subcommand, globals)?;
+    }
+    globals.truncate(inherited);
+    Ok(())
+}
+
+/// Converts a list of config items, failing on the first invalid one.
+fn convert<T: TryFrom<C, Error = miette::Report>, C>(items: Vec<C>) -> miette::Result<Vec<T>> {
+    items.into_iter().map(T::try_from).collect()
+}
+
+impl From<CfgExample> for Example {
+    fn from(cfg: CfgExample) -> Example {
+        Example {
+            command: cfg.command,
+            description: cfg.description,
+        }
+    }
+}
+
+impl TryFrom<CfgFlag> for Flag {
+    type Error = miette::Report;
+
+    fn try_from(cfg: CfgFlag) -> miette::Result<Flag> {
+        Ok(Flag {
+            name: cfg.name.clone(),
+            position: cfg.position.0,
+            var: cfg.var.unwrap_or_default(),
+            short: short_flag(cfg.short)?,
+            long: cfg.long,
+            aliases: aliases(cfg.aliases, cfg.visible_aliases, cfg.short_aliases)?,
+            global: cfg.global.unwrap_or(false),
+            description: cfg.description,
+            help: ArgHelp {
+                long_description: cfg.long_description,
+                heading: cfg.help_heading,
+                display_order: cfg.display_order,
+                next_line_help: cfg.next_line_help.unwrap_or(false),
+                hide: cfg.hide.unwrap_or(false),
+            },
+        })
+    }
+}
+
+impl TryFrom<CfgArg> for Arg {
+    type Error = miette::Report;
+
+    fn try_from(cfg: CfgArg) -> miette::Result<Arg> {
+        let passthrough = match (cfg.last.unwrap_or(false), cfg.passthrough.unwrap_or(false)) {
+            (false, false) => None,
+            (true, false) => Some(Passthrough::Last),
+            (false, true) => Some(Passthrough::Trailing),
+            (true, true) => miette::bail!("invalid arg '{}' (last and passthrough can not be combined)", cfg.name),
+        };
+        Ok(Arg {
+            name: cfg.name.clone(),
+            position: cfg.position.0,
+            var: cfg.var.unwrap_or_default(),
+            description: cfg.description,
+            help: ArgHelp {
+                long_description: cfg.long_description,
+                heading: cfg.help_heading,
+                display_order: cfg.display_order,
+                next_line_help: cfg.next_line_help.unwrap_or(false),
+                hide: cfg.hide.unwrap_or(false),
+            },
+            value_name: cfg.value_name.unwrap_or(cfg.name.to_uppercase()),
+            count: match cfg.count {
                 Some(s) => match s.as_str() {
                     "1" => Count::One,
                     "+" => Count::AtLeastOne,
                     "*" => Count::Any,
-                    _ => panic!("invalid count")
+                    _ => miette::bail!("invalid count '{s}' (must be 1, + or *)"),
                 },
+                None if passthrough.is_some() => Count::Any,
                 None => Count::One,
             },
+            passthrough: passthrough.unwrap_or(Passthrough::No),
+        })
+    }
+}
+
+impl From<CfgExitCodes> for ExitCodes {
+    fn from(cfg: CfgExitCodes) -> ExitCodes {
+        ExitCodes {
+            help: cfg.help.unwrap_or(0),
+            version: cfg.version.unwrap_or(0),
+            usage: cfg.usage.unwrap_or(2),
         }
     }
 }
 
-impl Into<Command> for CfgCommand {
-    fn into(self) -> Command {
-        Command {
-            name: self.name,
-            short_flag: short_flag(self.short_flag),
-            long_flag: self.long_flag,
-            require_subcommand: self.require_subcommand.unwrap_or(false),
-            description: self.description,
-            handler: self.handler,
-            always_call_handler: self.always_call_handler.unwrap_or(false),
-            flags: self.flags.iter().map(|flag| flag.clone().into()).collect(),
-            opts: self.opts.iter().map(|opt| opt.clone().into()).collect(),
-            args: self.args.iter().map(|arg| arg.clone().into()).collect(),
-            subcommands: self.subcommands.iter().map(|cmd| cmd.clone().into()).collect(),
+impl TryFrom<CfgCommand> for Command {
+    type Error = miette::Report;
+
+    fn try_from(cfg: CfgCommand) -> miette::Result<Command> {
+        if cfg.exec_handler == Some(true) && cfg.after_handler.is_some() {
+            miette::bail!("subcommand '{}' combines exec-handler with after-handler, which would never run", cfg.name);
+        }
+        if cfg.exec_handler == Some(true) && cfg.always_call_handler == Some(true) && !cfg.subcommands.is_empty() {
+            miette::bail!("subcommand '{}' combines exec-handler with always-call-handler, the handlers of its subcommands would never run", cfg.name);
         }
+        Ok(Command {
+            name: cfg.name,
+            short_flag: short_flag(cfg.short_flag)?,
+            long_flag: cfg.long_flag,
+            aliases: aliases(cfg.aliases, cfg.visible_aliases, cfg.short_aliases)?,
+            require_subcommand: cfg.require_subcommand.unwrap_or(false),
+            display_order: cfg.display_order,
+            hide: cfg.hide.unwrap_or(false),
+            description: cfg.description,
+            long_description: cfg.long_description,
+            before_help: cfg.before_help,
+            after_help: cfg.after_help,
+            examples: cfg.examples.unwrap_or_default().examples.into_iter().map(|example| example.into()).collect(),
+            next_line_help: cfg.next_line_help.unwrap_or(false),
+            help_template: cfg.help_template,
+            usage: cfg.usage,
+            handler: cfg.handler,
+            handler_args: handler_args(cfg.handler_args, &cfg.args)?,
+            exec_handler: cfg.exec_handler.unwrap_or(false),
+            hooks: Hooks {
+                before: cfg.before_handler,
+                after: cfg.after_handler,
+                error: cfg.error_handler,
+            },
+            always_call_handler: cfg.always_call_handler.unwrap_or(false),
+            flags: convert(cfg.flags)?,
+            opts: convert(cfg.opts)?,
+            args: convert(cfg.args)?,
+            subcommands: convert(cfg.subcommands)?,
+        })
     }
 }
 
-impl Into<App> for CfgApp {
-    fn into(self) -> App {
-        App {
-            name: self.name.clone(),
-            version: self.version,
-            infer_subcommands: self.infer_subcommands.unwrap_or(false),
-            args_override_self: self.args_override_self.unwrap_or(true),
-            command: Command {
-                name: self.name.unwrap_or("".to_string()),
-                short_flag: None,
-                long_flag: None,
-                require_subcommand: self.require_subcommand.unwrap_or(false),
-                description: self.description,
-                handler: self.handler,
-                always_call_handler: self.always_call_handler.unwrap_or(false),
-                flags: self.flags.iter().map(|flag| flag.clone().into()).collect(),
-                opts: self.opts.iter().map(|opt| opt.clone().into()).collect(),
-                args: self.args.iter().map(|arg| arg.clone().into()).collect(),
-                subcommands: self.subcommands.iter().map(|cmd| cmd.clone().into()).collect(),
-            }
+impl TryFrom<CfgApp> for App {
+    type Error = miette::Report;
+
+    fn try_from(cfg: CfgApp) -> miette::Result<App> {
+        let has_subcommands = !cfg.subcommands.is_empty() || cfg.external_subcommands.is_some();
+        if cfg.exec_handler == Some(true) && cfg.always_call_handler == Some(true) && has_subcommands {
+            miette::bail!("exec-handler can not be combined with always-call-handler, the handlers of subcommands would never run");
+        }
+        let unset_style = match cfg.unset_style.as_deref() {
+            Some("marker") | None => UnsetStyle::Marker(cfg.unset_suffix.unwrap_or("_not_set".to_string())),
+            Some("unset") => UnsetStyle::Unset,
+            Some("empty") => UnsetStyle::Empty,
+            Some("sentinel") => match cfg.unset_sentinel {
+                Some(sentinel) => UnsetStyle::Sentinel(sentinel),
+                None => miette::bail!("unset-style sentinel requires unset-sentinel"),
+            },
+            Some(other) => miette::bail!("invalid unset-style '{other}' (must be marker, unset, empty or sentinel)"),
+        };
+
+        let naming = VarNaming {
+            prefix: cfg.var_prefix.unwrap_or_default(),
+            case: match cfg.var_case.as_deref() {
+                Some("upper") => VarCase::Upper,
+                Some("lower") => VarCase::Lower,
+                Some("keep") | None => VarCase::Keep,
+                Some(other) => miette::bail!("invalid var-case '{other}' (must be upper, lower or keep)"),
+            },
+            namespace_subcommands: cfg.namespace_subcommands.unwrap_or(false),
+            marker_suffix: match &unset_style {
+                UnsetStyle::Marker(suffix) => Some(suffix.clone()),
+                _ => None,
+            },
+        };
+
+        let posix_list_style = match cfg.posix_list_style.as_deref() {
+            Some("quoted") | None => ListStyle::Quoted,
+            Some("set") => ListStyle::Set,
+            Some("numbered") => ListStyle::Numbered,
+            Some("lines") => ListStyle::Lines,
+            Some(other) => miette::bail!("invalid posix-list-style '{other}' (must be quoted, set, numbered or lines)"),
+        };
+
+        let wrap = match cfg.wrap.as_deref() {
+            Some("none") | None => Wrap::None,
+            Some("block") => Wrap::Block,
+            Some("function") => Wrap::Function(cfg.wrap_function.unwrap_or("__argparse_main".to_string())),
+            Some(other) => miette::bail!("invalid wrap '{other}' (must be none, block or function)"),
+        };
+
+        let mut command = Command {
+            name: cfg.name.clone().unwrap_or("".to_string()),
+            short_flag: None,
+            long_flag: None,
+            aliases: aliases(vec![], vec![], vec![])?,
+            require_subcommand: cfg.require_subcommand.unwrap_or(false),
+            display_order: None,
+            hide: false,
+            description: cfg.description,
+            long_description: cfg.long_description,
+            before_help: cfg.before_help,
+            after_help: cfg.after_help,
+            examples: cfg.examples.unwrap_or_default().examples.into_iter().map(|example| example.into()).collect(),
+            next_line_help: cfg.next_line_help.unwrap_or(false),
+            help_template: cfg.help_template,
+            usage: cfg.usage,
+            handler: cfg.handler,
+            handler_args: handler_args(cfg.handler_args, &cfg.args)?,
+            exec_handler: cfg.exec_handler.unwrap_or(false),
+            hooks: Hooks::default(),
+            always_call_handler: cfg.always_call_handler.unwrap_or(false),
+            flags: convert(cfg.flags)?,
+            opts: convert(cfg.opts)?,
+            args: convert(cfg.args)?,
+            subcommands: convert(cfg.subcommands)?,
+        };
+        check_globals(&command, &mut Vec::new())?;
+        naming.apply(&mut command, &mut vec![], &mut Vec::new())?;
+        if cfg.after_handler.is_some() && uses_exec_handler(&command) {
+            miette::bail!("after-handler can not be combined with exec-handler, it would never run");
+        }
+
+        Ok(App {
+            name: cfg.name,
+            version: cfg.version,
+            author: cfg.author,
+            homepage: cfg.homepage,
+            bug_report_url: cfg.bug_report_url,
+            multicall: cfg.multicall.unwrap_or(false),
+            infer_subcommands: cfg.infer_subcommands.unwrap_or(false),
+            args_override_self: cfg.args_override_self.unwrap_or(true),
+            declare_all: cfg.declare_all.unwrap_or(false),
+            unset_style,
+            posix_list_style,
+            function: cfg.function,
+            wrap,
+            subcommand_var: cfg.subcommand_var.unwrap_or("__argparse_subcommand".to_string()),
+            subcommand_path_var: cfg.subcommand_path_var.unwrap_or("__argparse_subcommand_path".to_string()),
+            hooks: Hooks {
+                before: cfg.before_handler,
+                after: cfg.after_handler,
+                error: cfg.error_handler,
+            },
+            on_parse_error: cfg.on_parse_error,
+            exit_codes: cfg.exit_codes.unwrap_or_default().into(),
+            color: match cfg.color {
+                Some(s) => match s.parse() {
+                    Ok(color) => color,
+                    Err(_) => miette::bail!("invalid color '{s}' (must be auto, always or never)"),
+                },
+                None => ColorChoice::Auto,
+            },
+            color_opt: cfg.color_opt.unwrap_or(false),
+            external_subcommands: cfg.external_subcommands,
+            plugin_dirs: cfg.plugin_dirs,
+            command,
+        })
+    }
+}
+
+/// The settings of an empty config,
+/// used when the actual config can not be parsed.
+impl Default for App {
+    fn default() -> App {
+        CfgApp::default().try_into().expect("the empty config is valid")
+    }
+}
+
+
+/// Blanks out everything but the children of the selected `app` block,
+/// so that spans in errors still point into the original config.
+/// Configs without `app` blocks are a single spec, a single `app` block is
+/// selected without `--spec`. Next to `app` blocks there can be no other nodes.
+fn select_spec(config_str: &str, spec: Option<&str>) -> miette::Result<String> {
+    let document = knus::parse_ast::<Span>("<config>", config_str)?;
+    let specs: Vec<&SpannedNode<Span>> = document.nodes.iter()
+        .filter(|node| &**node.node_name == "app")
+        .collect();
+    if !specs.is_empty() {
+        if let Some(node) = document.nodes.iter().find(|node| &**node.node_name != "app") {
+            miette::bail!("'{}' is outside of the app blocks, move it into the app block it applies to", &**node.node_name);
+        }
+    }
+    let selected = match spec {
+        None if specs.is_empty() => return Ok(config_str.to_string()),
+        None if specs.len() == 1 => specs[0],
+        None => {
+            let names: Vec<&str> = specs.iter().filter_map(|node| string_argument(node)).collect();
+            miette::bail!("config contains several specs, select one with --spec ({})", names.join(", "));
+        }
+        Some(name) => match specs.into_iter().find(|node| string_argument(node) == Some(name)) {
+            Some(node) => node,
+            None => miette::bail!("spec '{name}' not found in config"),
+        },
+    };
+    let Some(children) = &selected.children else {
+        return Ok(String::new());
+    };
+    // the span includes the braces
+    let Span(start, end) = *children.span();
+    Ok(config_str.char_indices().map(|(i, c)| match c {
+        '\n' => "\n".to_string(),
+        c if i <= start || i >= end - 1 => " ".repeat(c.len_utf8()),
+        c => c.to_string(),
+    }).collect())
+}
+
+/// The JSON Schema of configs, as accepted by all formats.
+pub fn json_schema() -> serde_json::Value {
+    serde_json::to_value(schemars::schema_for!(CfgApp)).unwrap()
+}
+
+/// The formats a config can be written in.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Format {
+    Kdl,
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl Format {
+    /// Detects the format from the extension of a config file, KDL is the default.
+    pub fn from_path(path: &Path) -> Format {
+        match path.extension().and_then(|x| x.to_str()) {
+            Some("json") => Format::Json,
+            Some("yaml" | "yml") => Format::Yaml,
+            Some("toml") => Format::Toml,
+            _ => Format::Kdl,
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "kdl" => Ok(Format::Kdl),
+            "json" => Ok(Format::Json),
+            "yaml" => Ok(Format::Yaml),
+            "toml" => Ok(Format::Toml),
+            _ => Err(format!("invalid config format '{s}' (must be kdl, json, yaml or toml)")),
         }
     }
 }
 
+/// Parses a config, `spec` selects one of the `app` blocks of a KDL config.
+/// Included files are relative to `dir`.
+pub fn parse(config_str: &str, format: Format, dir: &Path, spec: Option<&str>) -> miette::Result<App> {
+    let cfg: CfgApp = match format {
+        Format::Kdl => {
+            let resolved = fragments::resolve(config_str, dir)?;
+            // the selected spec keeps the layout of the resolved config
+            let config_str = select_spec(resolved.text.as_str(), spec)?;
+            knus::parse("<config>", config_str.as_str()).map_err(|err| resolved.locate(err))?
+        }
+        _ if spec.is_some() => miette::bail!("--spec is only supported for KDL configs"),
+        Format::Json => serde_json::from_str(config_str).into_diagnostic()?,
+        Format::Yaml => serde_norway::from_str(config_str).into_diagnostic()?,
+        Format::Toml => toml::from_str(config_str).into_diagnostic()?,
+    };
+    cfg.try_into()
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-pub fn parse(config_str: &str) -> miette::Result<App> {
-    Ok(knus::parse::<CfgApp>("<config>", config_str)?.into())
-}
\ No newline at end of file
+    const SPECS: &str = "app \"deploy\" {\n    name \"deploy\"\n}\napp \"ops\" {\n    name \"ops\"\n}\n";
+
+    fn parse_json(config: &str) -> miette::Result<App> {
+        parse(config, Format::Json, Path::new("."), None)
+    }
+
+    #[test]
+    fn json_rejects_unknown_fields_of_args() {
+        assert!(parse_json(r#"{"flags": [{"name": "v", "hide": true}]}"#).is_ok());
+        assert!(parse_json(r#"{"flags": [{"name": "v", "typo": 1}]}"#).is_err());
+        assert!(parse_json(r#"{"opts": [{"name": "v", "typo": 1}]}"#).is_err());
+        assert!(parse_json(r#"{"args": [{"name": "v", "typo": 1}]}"#).is_err());
+    }
+
+    #[test]
+    fn json_keeps_declaration_order() {
+        let app = parse_json(r#"{"args": [{"name": "a"}], "flags": [{"name": "b"}], "opts": [{"name": "c"}]}"#).unwrap();
+        let command = app.command;
+        assert!(command.args[0].position < command.flags[0].position);
+        assert!(command.flags[0].position < command.opts[0].position);
+    }
+
+    fn naming(prefix: &str, case: VarCase, namespace_subcommands: bool) -> VarNaming {
+        VarNaming { prefix: prefix.to_string(), case, namespace_subcommands, marker_suffix: None }
+    }
+
+    fn parse_kdl(config: &str) -> miette::Result<App> {
+        parse(config, Format::Kdl, Path::new("."), None)
+    }
+
+    #[test]
+    fn var_names_are_sanitized() {
+        let naming = naming("", VarCase::Keep, false);
+        assert_eq!(naming.var_name(&[], "dry-run"), "dry_run");
+        assert_eq!(naming.var_name(&[], "2fa"), "_2fa");
+        assert_eq!(naming.var_name(&[], ""), "_");
+        assert_eq!(naming.resolve(&[], "x", "my.var"), "my_var");
+    }
+
+    #[test]
+    fn var_names_use_case_prefix_and_namespace() {
+        assert_eq!(naming("", VarCase::Upper, false).var_name(&[], "dry-run"), "DRY_RUN");
+        assert_eq!(naming("", VarCase::Lower, false).var_name(&[], "Dry-Run"), "dry_run");
+        assert_eq!(naming("app_", VarCase::Keep, false).var_name(&[], "x"), "app_x");
+        let path = ["remote".to_string(), "add".to_string()];
+        assert_eq!(naming("", VarCase::Keep, true).var_name(&path, "name"), "remote_add_name");
+        assert_eq!(naming("", VarCase::Keep, false).var_name(&path, "name"), "name");
+        assert_eq!(naming("p-", VarCase::Upper, true).var_name(&path, "x"), "p_REMOTE_ADD_X");
+    }
+
+    #[test]
+    fn colliding_var_names_are_rejected() {
+        let err = parse_kdl("opt \"a-b\"\nopt \"a_b\"\n").unwrap_err();
+        assert!(err.to_string().contains("the variable of 'a_b' and the variable of 'a-b' are both named 'a_b'"), "{err}");
+        assert!(parse_kdl("opt \"a\"\nflag \"b\" var=\"a\"\n").is_err());
+        assert!(parse_kdl("opt \"a\"\nsubcommand \"s\" {\n    arg \"a\"\n}\n").is_err());
+        let namespaced = "namespace-subcommands true\nsubcommand \"a-b\" {\n    arg \"c\"\n}\nsubcommand \"a\" {\n    arg \"b_c\"\n}\n";
+        let err = parse_kdl(namespaced).unwrap_err();
+        assert!(err.to_string().contains("the variable of 'c' of subcommand 'a-b' are both"), "{err}");
+    }
+
+    #[test]
+    fn unset_markers_take_their_names() {
+        let err = parse_kdl("flag \"x\"\nopt \"x_not_set\"\n").unwrap_err();
+        assert!(err.to_string().contains("the variable of 'x_not_set' and the unset marker of 'x'"), "{err}");
+        assert!(parse_kdl("opt \"x_not_set\"\nflag \"x\"\n").is_err());
+        assert!(parse_kdl("unset-suffix \"_unset\"\nflag \"x\"\nopt \"x_not_set\"\n").is_ok());
+        assert!(parse_kdl("unset-style \"empty\"\nflag \"x\"\nopt \"x_not_set\"\n").is_ok());
+    }
+
+    #[test]
+    fn always_called_exec_handlers_are_rejected() {
+        assert!(parse_kdl("handler \"h\"\nexec-handler true\nalways-call-handler true\nsubcommand \"s\"\n").is_err());
+        assert!(parse_kdl("subcommand \"s\" {\n    exec-handler true\n    always-call-handler true\n    subcommand \"t\"\n}\n").is_err());
+        assert!(parse_kdl("handler \"h\"\nexec-handler true\nalways-call-handler true\n").is_ok());
+        assert!(parse_kdl("handler \"h\"\nexec-handler true\nsubcommand \"s\"\n").is_ok());
+    }
+
+    #[test]
+    fn sibling_subcommands_share_var_names() {
+        assert!(parse_kdl("subcommand \"a\" {\n    opt \"x\"\n}\nsubcommand \"b\" {\n    opt \"x\"\n}\n").is_ok());
+    }
+
+    #[test]
+    fn configs_without_app_blocks_are_kept() {
+        assert_eq!(select_spec("name \"x\"\n", None).unwrap(), "name \"x\"\n");
+    }
+
+    #[test]
+    fn single_app_block_is_selected_without_spec() {
+        let selected = select_spec("app \"x\" {\n    name \"x\"\n}\n", None).unwrap();
+        assert_eq!(selected, "         \n    name \"x\"\n \n");
+    }
+
+    #[test]
+    fn selected_spec_keeps_spans() {
+        let selected = select_spec(SPECS, Some("ops")).unwrap();
+        assert_eq!(selected.len(), SPECS.len());
+        assert_eq!(selected.find("name"), SPECS.find("name \"ops\""));
+        assert_eq!(selected.trim(), "name \"ops\"");
+    }
+
+    #[test]
+    fn nodes_outside_of_app_blocks_are_rejected() {
+        let err = select_spec("declare-all true\napp \"x\" {\n    name \"x\"\n}\n", None).unwrap_err();
+        assert!(err.to_string().contains("'declare-all' is outside of the app blocks"), "{err}");
+        assert!(select_spec(&format!("{SPECS}flag \"v\"\n"), Some("ops")).is_err());
+    }
+
+    #[test]
+    fn several_app_blocks_require_spec() {
+        let err = select_spec(SPECS, None).unwrap_err();
+        assert!(err.to_string().contains("(deploy, ops)"), "{err}");
+        assert!(select_spec(SPECS, Some("missing")).is_err());
+    }
+}
@@ -1,10 +1,13 @@
 use std::fmt::Write;
 use clap::builder::StyledStr;
+use clap::error::ErrorKind;
 use miette::Report;
+use crate::config::ExitCodes;
 
 pub struct Error {
     msg: StyledStr,
     use_stderr: bool,
+    kind: Option<ErrorKind>,
 }
 
 impl Error {
@@ -14,6 +17,23 @@ impl Error {
     pub fn message(&self) -> &StyledStr {
         &self.msg
     }
+    /// Config and I/O errors happen before the config is loaded,
+    /// so they always exit with 1.
+    pub fn exit_code(&self, codes: &ExitCodes) -> i32 {
+        match self.kind {
+            Some(ErrorKind::DisplayHelp) => codes.help,
+            Some(ErrorKind::DisplayVersion) => codes.version,
+            Some(ErrorKind::Io) | None => 1,
+            Some(_) => codes.usage,
+        }
+    }
+    /// The name of the error kind as passed to `on-parse-error` functions.
+    pub fn kind_name(&self) -> String {
+        match self.kind {
+            Some(kind) => format!("{kind:?}"),
+            None => "Config".to_string(),
+        }
+    }
 }
 
 impl From<Report> for Error {
@@ -23,6 +43,7 @@ impl From<Report> for Error {
         Error {
             msg,
             use_stderr: true,
+            kind: None,
         }
     }
 }
@@ -32,6 +53,7 @@ impl From<clap::Error> for Error {
         Error {
             msg: err.render(),
             use_stderr: err.use_stderr(),
+            kind: Some(err.kind()),
         }
     }
 }
@@ -43,6 +65,7 @@ impl From<std::io::Error> for Error {
         Error {
             msg,
             use_stderr: true,
+            kind: Some(ErrorKind::Io),
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,49 @@
+use std::collections::BTreeSet;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// The id of the arg that collects the arguments of a discovered external subcommand.
+pub const EXTERNAL_ARGS: &str = "__argparse_external_args";
+
+fn search_dirs(plugin_dirs: &[String]) -> Vec<PathBuf> {
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    plugin_dirs.iter().map(PathBuf::from)
+        .chain(std::env::split_paths(&path))
+        .collect()
+}
+
+fn is_executable(path: &Path) -> bool {
+    match path.metadata() {
+        Ok(metadata) => metadata.is_file() && metadata.permissions().mode() & 0o111 != 0,
+        Err(_) => false,
+    }
+}
+
+/// Finds the executable `<prefix>-<name>`, plugin dirs are searched before `PATH`.
+pub fn find(prefix: &str, name: &str, plugin_dirs: &[String]) -> Option<PathBuf> {
+    let file_name = format!("{prefix}-{name}");
+    search_dirs(plugin_dirs).into_iter()
+        .map(|dir| dir.join(&file_name))
+        .find(|path| is_executable(path))
+}
+
+/// Lists the names of all external subcommands available in the plugin dirs and `PATH`.
+pub fn discover(prefix: &str, plugin_dirs: &[String]) -> Vec<String> {
+    let prefix = format!("{prefix}-");
+    let mut found: BTreeSet<String> = BTreeSet::new();
+    for dir in search_dirs(plugin_dirs) {
+        let Ok(entries) = dir.read_dir() else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let file_name = entry.file_name();
+            let Some(name) = file_name.to_str().and_then(|x| x.strip_prefix(prefix.as_str())) else {
+                continue;
+            };
+            if !name.is_empty() && is_executable(&entry.path()) {
+                found.insert(name.to_string());
+            }
+        }
+    }
+    found.into_iter().collect()
+}
@@ -0,0 +1,220 @@
+use kdl::{KdlDocument, KdlEntry, KdlValue};
+
+/// Properties that come first, in this order, other properties follow them
+/// in their original order.
+const PROPERTY_ORDER: [&str; 5] = ["short", "long", "value-name", "default", "description"];
+
+/// Sort key of an entry, arguments stay in front of all properties.
+fn rank(entry: &KdlEntry) -> usize {
+    match entry.name() {
+        None => 0,
+        Some(name) => PROPERTY_ORDER.iter()
+            .position(|x| *x == name.value())
+            .map_or(PROPERTY_ORDER.len() + 1, |i| i + 1),
+    }
+}
+
+/// Sorts the entries of all nodes and writes raw strings as plain strings.
+fn normalize(document: &mut KdlDocument) {
+    for node in document.nodes_mut() {
+        node.entries_mut().sort_by_key(rank);
+        for entry in node.entries_mut() {
+            if let KdlValue::RawString(value) = entry.value_mut() {
+                *entry.value_mut() = KdlValue::String(std::mem::take(value));
+            }
+        }
+        if let Some(children) = node.children_mut() {
+            normalize(children);
+        }
+    }
+}
+
+/// Indents the comments in the text between two nodes, returning them and
+/// the text on the line of the next node or closing brace. Single blank lines
+/// are kept between comments, but not at the `start` or `end` of a block.
+/// Block comments are kept as they are.
+fn comments<'a>(text: &'a str, own_line: bool, indent: &str, start: bool, end: bool) -> (String, &'a str) {
+    let mut lines: Vec<&str> = text.split('\n').collect();
+    let last_line = lines.pop().unwrap_or("");
+    if !own_line && lines.first().is_some_and(|x| x.trim().is_empty()) {
+        // the rest of the line of the previous node
+        lines.remove(0);
+    }
+
+    let mut result = String::new();
+    let mut blank = false;
+    let mut comment_depth = 0;
+    for line in lines {
+        if comment_depth > 0 {
+            result.push_str(line);
+            result.push('\n');
+        } else if line.trim().is_empty() {
+            blank = !start || !result.is_empty();
+        } else {
+            if blank {
+                result.push('\n');
+                blank = false;
+            }
+            result.push_str(indent);
+            result.push_str(line.trim());
+            result.push('\n');
+        }
+        comment_depth += line.matches("/*").count();
+        comment_depth -= comment_depth.min(line.matches("*/").count());
+    }
+    if blank && !end {
+        result.push('\n');
+    }
+    (result, last_line.trim())
+}
+
+/// The text before an entry, a single space unless it holds comments or a
+/// line continuation. Those are kept, continued lines are indented one level
+/// deeper than their node.
+fn entry_leading(text: &str, indent: &str) -> String {
+    let trimmed = text.trim();
+    if trimmed.is_empty() {
+        return " ".to_string();
+    }
+    let continued = format!("\n{indent}    ");
+    let mut leading = format!(" {}", trimmed.split('\n').map(str::trim).collect::<Vec<_>>().join(&continued));
+    if text.trim_end_matches([' ', '\t']).ends_with('\n') {
+        leading.push_str(&continued);
+    } else {
+        leading.push(' ');
+    }
+    leading
+}
+
+/// Whether the trailing text of a node starts with slashdashed entries,
+/// which kdl can not format.
+fn slashdashed(trailing: Option<&str>) -> Option<&str> {
+    trailing.map(str::trim_start).filter(|x| x.starts_with("/-"))
+}
+
+/// Removes slashdashed entries after the last entry of nodes, so that the
+/// document can be formatted. `format_comments` puts them back.
+fn remove_slashdashed(document: &mut KdlDocument) {
+    for node in document.nodes_mut() {
+        if slashdashed(node.trailing()).is_some() {
+            node.set_trailing("\n");
+        }
+        if let Some(children) = node.children_mut() {
+            remove_slashdashed(children);
+        }
+    }
+}
+
+/// Replaces the text before nodes and before the end of blocks with the
+/// comments of the original document, as formatting drops blank lines and
+/// puts comments at the end of a block on the line of its closing brace.
+fn format_comments(original: &KdlDocument, document: &mut KdlDocument, depth: usize) {
+    let indent = "    ".repeat(depth);
+    let mut own_line = true;
+    for (i, (original, node)) in original.nodes().iter().zip(document.nodes_mut()).enumerate() {
+        let (mut leading, node_line) = comments(original.leading().unwrap_or(""), own_line, &indent, i == 0, false);
+        leading.push_str(&indent);
+        if !node_line.is_empty() {
+            leading.push_str(node_line);
+            leading.push(' ');
+        }
+        node.set_leading(leading);
+        for (original, entry) in original.entries().iter().zip(node.entries_mut()) {
+            entry.set_leading(entry_leading(original.leading().unwrap_or(""), &indent));
+        }
+        if let Some(trailing) = slashdashed(original.trailing()) {
+            node.set_trailing(format!(" {trailing}"));
+        }
+        own_line = original.trailing().is_some_and(|x| x.ends_with('\n'));
+
+        if let (Some(original), Some(children)) = (original.children(), node.children_mut()) {
+            format_comments(original, children, depth + 1);
+        }
+    }
+
+    let (mut trailing, end_line) = comments(original.trailing().unwrap_or(""), own_line, &indent, original.nodes().is_empty(), true);
+    trailing.push_str(&"    ".repeat(depth.saturating_sub(1)));
+    if !end_line.is_empty() {
+        trailing.push_str(end_line);
+        trailing.push(' ');
+    }
+    document.set_trailing(trailing);
+}
+
+/// Formats a KDL config canonically, keeping its comments.
+pub fn format(text: &str) -> miette::Result<String> {
+    let mut document: KdlDocument = text.parse()?;
+    normalize(&mut document);
+    let original = document.clone();
+    remove_slashdashed(&mut document);
+    document.fmt();
+    format_comments(&original, &mut document, 0);
+    Ok(document.to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r##"// the app
+name "example"
+opt "foo" description="how much foo" default="1" short="f" /* inline */ long=r#"foo"#
+
+
+/* block
+   comment */
+subcommand "bar" {
+  flag "with_foo" \ // continued
+          long="with-foo" short="W"
+  arg "key" /-count="+"
+  // at the end
+}
+"##;
+
+    /// The nodes and entries of a config, without any formatting.
+    fn values(document: &KdlDocument) -> Vec<String> {
+        let mut values = Vec::new();
+        for node in document.nodes() {
+            let mut entries: Vec<String> = node.entries().iter()
+                .map(|entry| format!("{:?}={:?}", entry.name().map(|x| x.value()), entry.value().as_string()))
+                .collect();
+            entries.sort();
+            values.push(format!("{} {}", node.name().value(), entries.join(" ")));
+            if let Some(children) = node.children() {
+                values.extend(self::values(children));
+            }
+        }
+        values
+    }
+
+    #[test]
+    fn orders_properties_and_keeps_comments() {
+        assert_eq!(format(CONFIG).unwrap(), r#"// the app
+name "example"
+opt "foo" short="f" /* inline */ long="foo" default="1" description="how much foo"
+
+/* block
+   comment */
+subcommand "bar" {
+    flag "with_foo" short="W" \ // continued
+        long="with-foo"
+    arg "key" /-count="+"
+    // at the end
+}
+"#);
+    }
+
+    #[test]
+    fn formatting_is_idempotent() {
+        let formatted = format(CONFIG).unwrap();
+        assert_eq!(format(&formatted).unwrap(), formatted);
+    }
+
+    #[test]
+    fn formatting_keeps_the_config() {
+        let original: KdlDocument = CONFIG.parse().unwrap();
+        let formatted: KdlDocument = format(CONFIG).unwrap().parse().unwrap();
+        assert_eq!(values(&formatted), values(&original));
+    }
+}
@@ -0,0 +1,364 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use knus::ast::{Literal, SpannedNode};
+use knus::span::Span;
+use miette::{Diagnostic, LabeledSpan, NamedSource, SourceCode};
+
+/// The first argument of a node, if it is a string.
+pub fn string_argument(node: &SpannedNode<Span>) -> Option<&str> {
+    match node.arguments.first().map(|arg| &*arg.literal) {
+        Some(Literal::String(value)) => Some(value),
+        _ => None,
+    }
+}
+
+fn required_argument<'a>(node: &'a SpannedNode<Span>, what: &str) -> miette::Result<&'a str> {
+    string_argument(node)
+        .ok_or_else(|| miette::miette!("{} requires {what}", &**node.node_name))
+}
+
+/// The text between the braces of a node.
+fn children_text(text: &Resolved, node: &SpannedNode<Span>) -> Resolved {
+    match &node.children {
+        Some(children) => {
+            let Span(start, end) = *children.span();
+            text.slice(start + 1, end - 1)
+        }
+        None => Resolved::default(),
+    }
+}
+
+/// A file the config was read from.
+#[derive(Debug)]
+struct Source {
+    name: String,
+    text: String,
+}
+
+/// A part of a resolved config and where in which file it comes from.
+#[derive(Debug, Clone)]
+struct Segment {
+    start: usize,
+    end: usize,
+    source: Rc<Source>,
+    offset: usize,
+}
+
+/// Config text that may be spliced together from several files,
+/// every part remembers the file and position it comes from.
+#[derive(Debug, Clone, Default)]
+pub struct Resolved {
+    pub text: String,
+    segments: Vec<Segment>,
+}
+
+impl Resolved {
+    fn file(name: &str, text: &str) -> Resolved {
+        let source = Rc::new(Source { name: name.to_string(), text: text.to_string() });
+        Resolved {
+            text: text.to_string(),
+            segments: vec![Segment { start: 0, end: text.len(), source, offset: 0 }],
+        }
+    }
+
+    fn slice(&self, start: usize, end: usize) -> Resolved {
+        let segments = self.segments.iter()
+            .filter(|segment| segment.start < end && segment.end > start)
+            .map(|segment| Segment {
+                start: segment.start.max(start) - start,
+                end: segment.end.min(end) - start,
+                source: segment.source.clone(),
+                offset: segment.offset + start.saturating_sub(segment.start),
+            })
+            .collect();
+        Resolved { text: self.text[start..end].to_string(), segments }
+    }
+
+    fn push(&mut self, other: &Resolved) {
+        let shift = self.text.len();
+        self.text.push_str(&other.text);
+        self.segments.extend(other.segments.iter().map(|segment| Segment {
+            start: segment.start + shift,
+            end: segment.end + shift,
+            ..segment.clone()
+        }));
+    }
+
+    /// The file a position of the text comes from and the position in that file.
+    fn origin(&self, pos: usize) -> Option<(&Rc<Source>, usize)> {
+        self.segments.iter()
+            .find(|segment| segment.start <= pos && pos < segment.end)
+            .or(self.segments.last().filter(|segment| segment.end == pos))
+            .map(|segment| (&segment.source, segment.offset + pos - segment.start))
+    }
+
+    /// Points the errors of decoding the text, or a text with the same
+    /// layout, into the files their nodes come from.
+    pub fn locate(&self, err: knus::Error) -> miette::Report {
+        let Some(errors) = err.related() else {
+            return err.into();
+        };
+        let errors = errors.map(|error| {
+            let mut source = None;
+            let mut labels = Vec::new();
+            for label in error.labels().into_iter().flatten() {
+                let Some((origin, offset)) = self.origin(label.offset()) else {
+                    continue;
+                };
+                if source.get_or_insert_with(|| origin.clone()).name == origin.name {
+                    labels.push(LabeledSpan::new(label.label().map(str::to_string), offset, label.len()));
+                }
+            }
+            Located {
+                message: error.to_string(),
+                help: error.help().map(|help| help.to_string()),
+                source: source.map(|source| NamedSource::new(&source.name, source.text.clone())),
+                labels,
+            }
+        }).collect();
+        LocatedErrors { errors }.into()
+    }
+}
+
+/// A decoding error that points into the file the failing node comes from.
+#[derive(Debug)]
+struct Located {
+    message: String,
+    help: Option<String>,
+    source: Option<NamedSource>,
+    labels: Vec<LabeledSpan>,
+}
+
+impl fmt::Display for Located {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Located {}
+
+impl Diagnostic for Located {
+    fn help<'a>(&'a self) -> Option<Box<dyn fmt::Display + 'a>> {
+        self.help.as_ref().map(|help| Box::new(help) as Box<dyn fmt::Display>)
+    }
+
+    fn source_code(&self) -> Option<&dyn SourceCode> {
+        self.source.as_ref().map(|source| source as &dyn SourceCode)
+    }
+
+    fn labels(&self) -> Option<Box<dyn Iterator<Item=LabeledSpan> + '_>> {
+        Some(Box::new(self.labels.iter().cloned()))
+    }
+}
+
+/// The decoding errors of a resolved config, reported like those of knus.
+#[derive(Debug)]
+struct LocatedErrors {
+    errors: Vec<Located>,
+}
+
+impl fmt::Display for LocatedErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("error parsing KDL")
+    }
+}
+
+impl std::error::Error for LocatedErrors {}
+
+impl Diagnostic for LocatedErrors {
+    fn related<'a>(&'a self) -> Option<Box<dyn Iterator<Item=&'a dyn Diagnostic> + 'a>> {
+        Some(Box::new(self.errors.iter().map(|error| error as &dyn Diagnostic)))
+    }
+}
+
+/// Replaces nodes at any depth with the text returned by `replace`,
+/// the children of nodes that are not replaced are visited.
+fn splice(file_name: &str, text: &Resolved, replace: &mut impl FnMut(&SpannedNode<Span>) -> miette::Result<Option<Resolved>>) -> miette::Result<Resolved> {
+    fn collect(nodes: &[SpannedNode<Span>], edits: &mut Vec<(Span, Resolved)>,
+               replace: &mut impl FnMut(&SpannedNode<Span>) -> miette::Result<Option<Resolved>>) -> miette::Result<()> {
+        for node in nodes {
+            match replace(node)? {
+                Some(replacement) => edits.push((node.span().clone(), replacement)),
+                None => if let Some(children) = &node.children {
+                    collect(children, edits, replace)?;
+                },
+            }
+        }
+        Ok(())
+    }
+
+    let document = knus::parse_ast::<Span>(file_name, &text.text)?;
+    let mut edits = Vec::new();
+    collect(&document.nodes, &mut edits, replace)?;
+
+    let mut spliced = Resolved::default();
+    let mut pos = 0;
+    for (Span(start, end), replacement) in edits {
+        spliced.push(&text.slice(pos, start));
+        spliced.push(&replacement);
+        // the span of a node includes its terminator, which is kept
+        pos = match text.text[..end].chars().next_back() {
+            Some('\n' | ';') => end - 1,
+            _ => end,
+        };
+    }
+    spliced.push(&text.slice(pos, text.text.len()));
+    Ok(spliced)
+}
+
+/// Replaces `include "file.kdl"` nodes with the contents of the file,
+/// relative paths are relative to the including file.
+fn expand_includes(file_name: &str, text: &Resolved, dir: &Path, stack: &mut Vec<PathBuf>) -> miette::Result<Resolved> {
+    splice(file_name, text, &mut |node| {
+        if &**node.node_name != "include" {
+            return Ok(None);
+        }
+        let path = dir.join(required_argument(node, "a path")?);
+        let canonical = path.canonicalize()
+            .map_err(|err| miette::miette!("cannot include {}: {err}", path.display()))?;
+        if stack.contains(&canonical) {
+            miette::bail!("{} includes itself", path.display());
+        }
+        let included = std::fs::read_to_string(&path)
+            .map_err(|err| miette::miette!("cannot include {}: {err}", path.display()))?;
+
+        stack.push(canonical);
+        let name = path.to_string_lossy();
+        let expanded = expand_includes(&name, &Resolved::file(&name, &included), path.parent().unwrap_or(dir), stack);
+        stack.pop();
+        expanded.map(Some)
+    })
+}
+
+/// Collects the bodies of all `template "name" { ... }` nodes.
+fn collect_templates(text: &Resolved, nodes: &[SpannedNode<Span>], templates: &mut HashMap<String, Resolved>) -> miette::Result<()> {
+    for node in nodes {
+        if &**node.node_name == "template" {
+            let name = required_argument(node, "a name")?;
+            if templates.insert(name.to_string(), children_text(text, node)).is_some() {
+                miette::bail!("template '{name}' is defined more than once");
+            }
+        } else if let Some(children) = &node.children {
+            collect_templates(text, children, templates)?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes template definitions and replaces `use "name"` nodes with the body
+/// of the template, templates can use other templates.
+fn expand_uses(text: &Resolved, templates: &HashMap<String, Resolved>, stack: &mut Vec<String>) -> miette::Result<Resolved> {
+    splice("<config>", text, &mut |node| {
+        match &**node.node_name {
+            "template" => Ok(Some(Resolved::default())),
+            "use" => {
+                let name = required_argument(node, "a template name")?;
+                let Some(body) = templates.get(name) else {
+                    miette::bail!("template '{name}' is not defined");
+                };
+                if stack.iter().any(|x| x == name) {
+                    miette::bail!("template '{name}' uses itself");
+                }
+
+                stack.push(name.to_string());
+                let expanded = expand_uses(body, templates, stack);
+                stack.pop();
+                expanded.map(Some)
+            }
+            _ => Ok(None),
+        }
+    })
+}
+
+/// Resolves `include`, `template` and `use` nodes, so that the result
+/// only contains nodes of the config schema.
+pub fn resolve(text: &str, dir: &Path) -> miette::Result<Resolved> {
+    let text = expand_includes("<config>", &Resolved::file("<config>", text), dir, &mut Vec::new())?;
+
+    let mut templates = HashMap::new();
+    collect_templates(&text, &knus::parse_ast::<Span>("<config>", &text.text)?.nodes, &mut templates)?;
+    expand_uses(&text, &templates, &mut Vec::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory for the files of one test, removed when dropped.
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(name: &str) -> TestDir {
+            let dir = std::env::temp_dir().join(format!("clap-sh-fragments-{}-{name}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            TestDir(dir)
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[derive(Debug, knus::Decode)]
+    struct Flag {
+        #[knus(argument)]
+        #[allow(dead_code)]
+        name: String,
+    }
+
+    #[test]
+    fn includes_are_expanded() {
+        let dir = TestDir::new("include");
+        std::fs::write(dir.0.join("flags.kdl"), "flag \"v\" short=\"v\"\n").unwrap();
+        let resolved = resolve("name \"x\"\ninclude \"flags.kdl\"\n", &dir.0).unwrap().text;
+        assert!(resolved.contains("flag \"v\" short=\"v\""), "{resolved}");
+        assert!(!resolved.contains("include"), "{resolved}");
+    }
+
+    #[test]
+    fn include_cycles_are_errors() {
+        let dir = TestDir::new("cycle");
+        std::fs::write(dir.0.join("a.kdl"), "include \"b.kdl\"\n").unwrap();
+        std::fs::write(dir.0.join("b.kdl"), "include \"a.kdl\"\n").unwrap();
+        let err = resolve("include \"a.kdl\"\n", &dir.0).unwrap_err();
+        assert!(err.to_string().contains("includes itself"), "{err}");
+    }
+
+    #[test]
+    fn templates_are_expanded() {
+        let resolved = resolve("template \"t\" {\n    flag \"v\"\n}\nsubcommand \"s\" {\n    use \"t\"\n}\n", Path::new(".")).unwrap().text;
+        let words: Vec<&str> = resolved.split_whitespace().collect();
+        assert_eq!(words.join(" "), "subcommand \"s\" { flag \"v\" }");
+    }
+
+    #[test]
+    fn template_recursion_is_an_error() {
+        let config = "template \"a\" {\n    use \"b\"\n}\ntemplate \"b\" {\n    use \"a\"\n}\nuse \"a\"\n";
+        let err = resolve(config, Path::new(".")).unwrap_err();
+        assert!(err.to_string().contains("template 'a' uses itself"), "{err}");
+    }
+
+    #[test]
+    fn replaced_nodes_keep_their_terminator() {
+        let resolved = resolve("template \"t\" {\n    flag \"v\"\n}; flag \"w\"\n", Path::new(".")).unwrap().text;
+        assert_eq!(resolved, "; flag \"w\"\n");
+    }
+
+    #[test]
+    fn errors_point_into_included_files() {
+        let dir = TestDir::new("locate");
+        std::fs::write(dir.0.join("flags.kdl"), "flag \"v\"\n\nflag \"w\" bogus=1\n").unwrap();
+        let resolved = resolve("flag \"a\"\ninclude \"flags.kdl\"\nflag \"b\"\n", &dir.0).unwrap();
+        let err = knus::parse::<Vec<Flag>>("<config>", &resolved.text).unwrap_err();
+        let located = resolved.locate(err);
+        let error = located.related().unwrap().next().unwrap();
+        let label = error.labels().unwrap().next().unwrap();
+        let contents = error.source_code().unwrap().read_span(label.inner(), 0, 0).unwrap();
+        assert_eq!(contents.name(), Some(dir.0.join("flags.kdl").to_string_lossy().as_ref()));
+        assert_eq!(contents.line(), 2);
+    }
+}
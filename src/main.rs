@@ -1,22 +1,91 @@
-use std::collections::HashMap;
-use std::io::{read_to_string, Read};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::io::read_to_string;
+use std::fmt::Write;
 use clap::ArgMatches;
-use crate::config::Count;
+use clap::builder::StyledStr;
+use indexmap::IndexMap;
+use miette::IntoDiagnostic;
+use crate::config::{Count, Passthrough, Wrap};
 use crate::error::Error;
+use crate::external::EXTERNAL_ARGS;
 use crate::shell::Shell;
 
 mod config;
 mod shell;
 mod error;
+mod external;
+mod fragments;
+mod schema;
+mod fmt;
+
+/// Fills in the app metadata placeholders of a help template.
+/// `{version}` and `{author}` are also supported by clap itself,
+/// but only on the top level command.
+fn expand_template(template: &str, app: &config::App) -> String {
+    let placeholders = [
+        ("{version}", &app.version),
+        ("{author}", &app.author),
+        ("{homepage}", &app.homepage),
+        ("{bug-report}", &app.bug_report_url),
+    ];
+    let mut expanded = template.to_string();
+    for (placeholder, value) in placeholders {
+        expanded = expanded.replace(placeholder, value.as_deref().unwrap_or(""));
+    }
+    expanded
+}
 
-fn build_command(config: &config::Command) -> clap::Command {
+/// Builds the clap command, subcommands inherit the help template of their parent.
+///
+/// Global opts and flags are added to every subcommand instead of using clap's
+/// `global(true)`, which only keeps the values of the deepest subcommand they occur in.
+/// This way `handle_matches` can merge the occurrences on all levels, the config
+/// makes sure that subcommands do not declare args with the same names.
+fn build_command(config: &config::Command, app: &config::App, help_template: Option<&String>, globals: &[clap::Arg]) -> clap::Command {
     let mut cmd = clap::Command::new(&config.name)
         .subcommand_required(config.require_subcommand);
 
+    let help_template = config.help_template.as_ref().or(help_template);
+    if let Some(help_template) = help_template {
+        cmd = cmd.help_template(expand_template(help_template, app));
+    }
+
+    if let Some(usage) = &config.usage {
+        cmd = cmd.override_usage(usage);
+    }
+
     if let Some(description) = &config.description {
         cmd = cmd.about(description);
     }
 
+    if let Some(long_description) = &config.long_description {
+        cmd = cmd.long_about(long_description);
+    }
+
+    if let Some(before_help) = &config.before_help {
+        cmd = cmd.before_help(before_help);
+    }
+
+    if let Some(after_help) = &config.after_help {
+        cmd = cmd.after_help(after_help);
+    }
+
+    if !config.examples.is_empty() {
+        let mut after_long_help = StyledStr::new();
+        if let Some(after_help) = &config.after_help {
+            write!(after_long_help, "{after_help}\n\n").unwrap();
+        }
+        write_examples(&mut after_long_help, &config.examples);
+        cmd = cmd.after_long_help(after_long_help);
+    }
+
+    if let Some(display_order) = config.display_order {
+        cmd = cmd.display_order(display_order);
+    }
+
+    cmd = cmd.next_line_help(config.next_line_help).hide(config.hide);
+
     if let Some(short) = &config.short_flag {
         cmd = cmd.short_flag(*short);
     }
@@ -25,25 +94,70 @@ fn build_command(config: &config::Command) -> clap::Command {
         cmd = cmd.long_flag(long);
     }
 
+    cmd = cmd.aliases(&config.aliases.hidden)
+        .visible_aliases(&config.aliases.visible)
+        .short_flag_aliases(config.aliases.short.iter().copied());
+
+    let mut globals = globals.to_vec();
+    for global in &globals {
+        cmd = cmd.arg(global);
+    }
+
     for flag in &config.flags {
-        cmd = cmd.arg(build_flag(&flag));
+        cmd = cmd.arg(build_flag(flag));
+        if flag.global {
+            globals.push(build_flag(flag));
+        }
     }
 
     for opt in &config.opts {
-        cmd = cmd.arg(build_opt(&opt));
+        cmd = cmd.arg(build_opt(opt));
+        if opt.global {
+            globals.push(build_opt(opt));
+        }
     }
 
     for arg in &config.args {
-        cmd = cmd.arg(build_arg(&arg));
+        cmd = cmd.arg(build_arg(arg));
     }
 
     for subcommand in &config.subcommands {
-        cmd = cmd.subcommand(build_command(&subcommand));
+        cmd = cmd.subcommand(build_command(subcommand, app, help_template, &globals));
     }
 
     cmd
 }
 
+/// Renders the examples section that is shown with `--help`.
+fn write_examples(help: &mut StyledStr, examples: &[config::Example]) {
+    write!(help, "{}", color_print::cstr!("<bold><underline>Examples:</underline></bold>")).unwrap();
+    for example in examples {
+        write!(help, "\n  {}", color_print::cformat!("<bold>{}</bold>", example.command)).unwrap();
+        if let Some(description) = &example.description {
+            write!(help, "\n          {description}").unwrap();
+        }
+    }
+}
+
+fn build_help(mut arg: clap::Arg, config: &config::ArgHelp) -> clap::Arg {
+    if let Some(long_description) = &config.long_description {
+        arg = arg.long_help(long_description);
+    }
+    if let Some(heading) = &config.heading {
+        arg = arg.help_heading(heading);
+    }
+    if let Some(display_order) = config.display_order {
+        arg = arg.display_order(display_order);
+    }
+    arg.next_line_help(config.next_line_help).hide(config.hide)
+}
+
+fn build_aliases(arg: clap::Arg, config: &config::Aliases) -> clap::Arg {
+    arg.aliases(&config.hidden)
+        .visible_aliases(&config.visible)
+        .short_aliases(config.short.iter().copied())
+}
+
 fn build_flag(config: &config::Flag) -> clap::Arg {
     let mut arg = clap::Arg::new(&config.name).action(clap::ArgAction::Count);
     if let Some(short) = config.short {
@@ -52,24 +166,30 @@ fn build_flag(config: &config::Flag) -> clap::Arg {
     if let Some(long) = &config.long {
         arg = arg.long(long);
     }
+    arg = build_aliases(arg, &config.aliases);
     if let Some(description) = &config.description {
         arg = arg.help(description);
     }
+    arg = build_help(arg, &config.help);
 
     arg
 }
 
 fn build_opt(config: &config::Opt) -> clap::Arg {
-    let mut arg = clap::Arg::new(&config.name).value_name(&config.value_name);
+    let mut arg = clap::Arg::new(&config.name)
+        .value_name(&config.value_name)
+        .value_parser(clap::value_parser!(OsString));
     if let Some(short) = config.short {
         arg = arg.short(short);
     }
     if let Some(long) = &config.long {
         arg = arg.long(long);
     }
+    arg = build_aliases(arg, &config.aliases);
     if let Some(description) = &config.description {
         arg = arg.help(description);
     }
+    arg = build_help(arg, &config.help);
     if config.repeated {
         arg = arg.action(clap::ArgAction::Append);
     }
@@ -77,11 +197,15 @@ fn build_opt(config: &config::Opt) -> clap::Arg {
     arg
 }
 fn build_arg(config: &config::Arg) -> clap::Arg {
-    let mut arg = clap::Arg::new(&config.name).value_name(&config.value_name).required(true);
+    let mut arg = clap::Arg::new(&config.name)
+        .value_name(&config.value_name)
+        .value_parser(clap::value_parser!(OsString))
+        .required(true);
 
     if let Some(description) = &config.description {
         arg = arg.help(description);
     }
+    arg = build_help(arg, &config.help);
 
     match config.count {
         Count::One => (),
@@ -91,8 +215,16 @@ fn build_arg(config: &config::Arg) -> clap::Arg {
         Count::Any => {
             arg = arg.num_args(0..)
         }
-        Count::Exactly(_) => {}
-        Count::MinMax(_, _) => {}
+    }
+
+    match config.passthrough {
+        Passthrough::No => (),
+        Passthrough::Last => {
+            arg = arg.last(true).allow_hyphen_values(true).required(false)
+        }
+        Passthrough::Trailing => {
+            arg = arg.trailing_var_arg(true).allow_hyphen_values(true).required(false)
+        }
     }
 
     arg
@@ -102,117 +234,420 @@ fn build_arg(config: &config::Arg) -> clap::Arg {
 #[derive(Debug, Clone)]
 enum VarValue {
     Unset,
-    Val(String),
-    List(Vec<String>),
+    Val(OsString),
+    List(Vec<OsString>),
+}
+
+
+/// The variables to set, in the order they are emitted.
+type Vars = IndexMap<String, VarValue>;
+
+/// Inserts the variables of one command ordered by the declaration of their
+/// opts, flags and args. Variables that were already declared keep their place.
+fn insert_declared(vars: &mut Vars, mut declared: Vec<(usize, &String, VarValue)>) {
+    declared.sort_by_key(|(position, _, _)| *position);
+    for (_, name, value) in declared {
+        vars.insert(name.clone(), value);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Handler {
+    pub name: String,
+    /// The executable of an external subcommand, which is called instead of `name`.
+    pub executable: Option<PathBuf>,
+    pub args: Vec<OsString>,
+    pub exec: bool,
+    pub hooks: config::Hooks,
+}
+
+impl Handler {
+    fn new(config: &config::Command, name: &str, vars: &Vars) -> Handler {
+        let mut args = Vec::new();
+        for arg_name in &config.handler_args {
+            let arg = config.args.iter().find(|arg| &arg.name == arg_name).unwrap();
+            match vars.get(&arg.var) {
+                Some(VarValue::Val(v)) => args.push(v.clone()),
+                Some(VarValue::List(vs)) => args.extend(vs.iter().cloned()),
+                _ => {}
+            }
+        }
+        Handler {
+            name: name.to_string(),
+            executable: None,
+            args,
+            exec: config.exec_handler,
+            hooks: config.hooks.clone(),
+        }
+    }
+
+    /// Whether the handler replaces the shell, which it can't in function mode.
+    pub fn execs(&self, config: &config::App) -> bool {
+        self.exec && config.function.is_none()
+    }
 }
 
 
-fn handle_matches(matches: &ArgMatches, config: &config::Command, vars: &mut HashMap<String, VarValue>, handlers: &mut Vec<String>) {
+/// Lists the functions the generated code calls, in the order they are called.
+/// Handlers that are exec'd are left out, they must be executables.
+fn required_functions(handlers: &[Handler], config: &config::App) -> Vec<String> {
+    let hooks = &config.hooks;
+    let mut functions: Vec<&String> = Vec::new();
+    functions.extend(&hooks.before);
+    for handler in handlers {
+        functions.extend(&handler.hooks.before);
+        if !handler.execs(config) {
+            functions.push(&handler.name);
+        }
+        functions.extend(&handler.hooks.after);
+        functions.extend(&handler.hooks.error);
+    }
+    functions.extend(&hooks.after);
+    functions.extend(&hooks.error);
+
+    let mut unique: Vec<String> = Vec::new();
+    for function in functions {
+        if !unique.contains(function) {
+            unique.push(function.clone());
+        }
+    }
+    unique
+}
+
+
+/// Declares every variable of the command tree, so that scripts also work
+/// with `set -u` when a branch of the tree was not taken.
+fn declare_vars(config: &config::Command, vars: &mut Vars) {
+    let mut declared = Vec::new();
     for opt in &config.opts {
+        declared.push((opt.position, &opt.var, if opt.repeated {
+            VarValue::List(vec![])
+        } else {
+            VarValue::Unset
+        }));
+    }
+
+    for flag in &config.flags {
+        declared.push((flag.position, &flag.var, VarValue::Unset));
+    }
+
+    for arg in &config.args {
+        declared.push((arg.position, &arg.var, match arg.count {
+            Count::One => VarValue::Unset,
+            _ => VarValue::List(vec![]),
+        }));
+    }
+    insert_declared(vars, declared);
+
+    for cmd in &config.subcommands {
+        declare_vars(cmd, vars);
+    }
+}
+
+
+/// The matches a global opt or flag can occur in: those of the command it is
+/// declared in, followed by those of the subcommands on the matched path.
+/// External subcommands have no globals and end the path.
+fn arg_levels<'a>(matches: &'a ArgMatches, config: &'a config::Command, global: bool) -> impl Iterator<Item=&'a ArgMatches> {
+    std::iter::successors(Some((matches, config)), move |(m, c)| {
+        let (name, m) = m.subcommand().filter(|_| global)?;
+        let c = c.subcommands.iter().find(|cmd| cmd.name == name)?;
+        Some((m, c))
+    }).map(|(m, _)| m)
+}
+
+/// The name and arguments of an external subcommand.
+fn external_subcommand(name: &str, matches: &ArgMatches) -> (String, Vec<OsString>) {
+    let args = matches.try_get_many::<OsString>(EXTERNAL_ARGS)
+        .or_else(|_| matches.try_get_many::<OsString>(""))
+        .ok()
+        .flatten()
+        .map(|v| v.cloned().collect())
+        .unwrap_or_default();
+    (name.to_string(), args)
+}
+
+fn handle_matches(matches: &ArgMatches, config: &config::Command, vars: &mut Vars, handlers: &mut Vec<Handler>, path: &mut Vec<String>, external: &mut Option<(String, Vec<OsString>)>) {
+    let mut declared = Vec::new();
+    for opt in &config.opts {
+        let levels = || arg_levels(matches, config, opt.global);
         let val = if opt.repeated {
-            VarValue::List(match matches.get_many::<String>(opt.name.as_str()) {
-                None => if let Some(default) = &opt.default {
-                    vec![default.to_string()]
-                } else {
-                    vec![]
-                },
-                Some(v) => v.map(|x| x.to_string()).collect(),
+            let values: Vec<OsString> = levels()
+                .filter_map(|m| m.get_many::<OsString>(opt.name.as_str()))
+                .flatten()
+                .cloned()
+                .collect();
+            VarValue::List(if values.is_empty() {
+                opt.default.iter().map(OsString::from).collect()
+            } else {
+                values
             })
         } else {
-            match matches.get_one::<String>(opt.name.as_str()) {
+            match levels().filter_map(|m| m.get_one::<OsString>(opt.name.as_str())).last() {
                 None => if let Some(default) = &opt.default {
-                    VarValue::Val(default.to_string())
+                    VarValue::Val(default.into())
                 } else {
                     VarValue::Unset
                 },
                 Some(v) => VarValue::Val(v.clone()),
             }
         };
-        vars.insert(opt.name.clone(), val);
+        declared.push((opt.position, &opt.var, val));
     }
 
     for flag in &config.flags {
-        let count = matches.get_count(flag.name.as_str());
-        vars.insert(flag.name.clone(), if count == 0 {
+        let count: u32 = arg_levels(matches, config, flag.global)
+            .map(|m| m.get_count(flag.name.as_str()) as u32)
+            .sum();
+        declared.push((flag.position, &flag.var, if count == 0 {
             VarValue::Unset
         } else {
-            VarValue::Val(count.to_string())
-        });
+            VarValue::Val(count.to_string().into())
+        }));
     }
 
     for arg in &config.args {
         match arg.count {
             Count::One => {
-                vars.insert(arg.name.clone(), match matches.get_one::<String>(arg.name.as_str()) {
+                declared.push((arg.position, &arg.var, match matches.get_one::<OsString>(arg.name.as_str()) {
                     None => VarValue::Unset,
                     Some(v) => VarValue::Val(v.clone()),
-                });
+                }));
             }
             _ => {
-                vars.insert(arg.name.clone(), match matches.get_many::<String>(arg.name.as_str()) {
+                declared.push((arg.position, &arg.var, match matches.get_many::<OsString>(arg.name.as_str()) {
                     None => VarValue::List(vec![]),
-                    Some(v) => VarValue::List(v.map(|x| x.clone()).collect()),
-                });
+                    Some(v) => VarValue::List(v.cloned().collect()),
+                }));
             }
         }
     }
+    insert_declared(vars, declared);
 
 
     match matches.subcommand() {
         None => {
             if let Some(handler) = &config.handler {
-                handlers.push(handler.to_string());
+                handlers.push(Handler::new(config, handler, vars));
             }
         }
         Some((name, matches)) => {
             if config.always_call_handler {
                 if let Some(handler) = &config.handler {
-                    handlers.push(handler.to_string());
+                    handlers.push(Handler::new(config, handler, vars));
                 }
             }
 
-            for cmd in &config.subcommands {
-                if cmd.name == name {
-                    handle_matches(matches, cmd, vars, handlers);
-                    break;
-                }
+            path.push(name.to_string());
+
+            match config.subcommands.iter().find(|cmd| cmd.name == name) {
+                Some(cmd) => handle_matches(matches, cmd, vars, handlers, path, external),
+                None => *external = Some(external_subcommand(name, matches)),
             }
         }
     }
 }
 
 
-fn do_things<'a>(shell: &Box<dyn Shell>, config: Option<&String>, progname: Option<String>, args: impl Iterator<Item=&'a String>) -> Result<(), Error> {
-    let config_str = if let Some(str) = config {
+const COLOR_ARG: &str = "__argparse_color";
+
+/// Finds the value of the injected `--color` opt without parsing the arguments,
+/// so that it also applies to errors from parsing them.
+fn color_from_args<'a>(args: impl Iterator<Item=&'a OsString>) -> Option<clap::ColorChoice> {
+    let mut args = args.take_while(|arg| arg.as_os_str() != "--");
+    while let Some(arg) = args.next() {
+        let value = if arg == "--color" {
+            args.next().and_then(|x| x.to_str())
+        } else {
+            arg.to_str().and_then(|x| x.strip_prefix("--color="))
+        };
+        if let Some(choice) = value.and_then(|x| x.parse().ok()) {
+            return Some(choice);
+        }
+    }
+    None
+}
+
+/// Reads the config given by `-c`, `-f` or stdin, along with its format.
+fn read_config(matches: &ArgMatches) -> std::io::Result<(String, config::Format)> {
+    let file = matches.get_one::<PathBuf>("config-file");
+    let config_str = if let Some(str) = matches.get_one::<String>("config") {
         str.to_string()
+    } else if let Some(file) = file {
+        std::fs::read_to_string(file)?
     } else {
         read_to_string(std::io::stdin())?
     };
 
-    let config = config::parse(config_str.as_str())?;
+    let format = match matches.get_one::<String>("config-format") {
+        Some(format) => format.parse().unwrap(),
+        None => file.map(|file| config::Format::from_path(file)).unwrap_or(config::Format::Kdl),
+    };
+    Ok((config_str, format))
+}
+
+fn load_config(matches: &ArgMatches) -> Result<config::App, Error> {
+    let (config_str, format) = read_config(matches)?;
+    let file = matches.get_one::<PathBuf>("config-file");
+    let dir = file.and_then(|file| file.parent()).unwrap_or(Path::new("."));
+    let spec = matches.get_one::<String>("spec").map(|x| x.as_str());
 
-    let progname = vec![progname.or(config.name).expect("missing program name, supply in config or via command line")];
+    Ok(config::parse(config_str.as_str(), format, dir, spec)?)
+}
 
-    let args = progname.iter().chain(args.map(|x|x));
+/// Formats the config given by `-c`, `-f` or stdin, with `--check` exits
+/// with 1 if it is not formatted.
+fn format_config(matches: &ArgMatches) -> miette::Result<()> {
+    let (config_str, format) = read_config(matches).into_diagnostic()?;
+    let file = matches.get_one::<PathBuf>("config-file");
+    if format != config::Format::Kdl {
+        miette::bail!("only KDL configs can be formatted");
+    }
 
-    let mut cmd = build_command(&config.command)
+    let formatted = fmt::format(&config_str)?;
+    if matches.get_flag("check") {
+        if formatted != config_str {
+            eprintln!("{} is not formatted", file.map_or("config".into(), |file| file.display().to_string()));
+            std::process::exit(1);
+        }
+    } else if let Some(file) = file {
+        if formatted != config_str {
+            std::fs::write(file, formatted).into_diagnostic()?;
+        }
+    } else {
+        print!("{formatted}");
+    }
+    Ok(())
+}
+
+/// Builds the clap command of the app, `discover` adds the external subcommands
+/// found in the plugin dirs and `PATH`, so that they are listed in help and errors.
+fn build_app(config: &config::App, progname: &str, discover: bool) -> clap::Command {
+    let mut cmd = build_command(&config.command, config, None, &[])
         .infer_subcommands(config.infer_subcommands)
         .args_override_self(config.args_override_self);
 
-    if let Some(version) = config.version {
+    if let Some(version) = &config.version {
         cmd = cmd.version(version);
     }
 
-    let matches = cmd.clone().try_get_matches_from(args)?;
+    if let Some(author) = &config.author {
+        cmd = cmd.author(author);
+    }
 
-    let mut vars: HashMap<String, VarValue> = HashMap::new();
-    let mut handlers: Vec<String> = Vec::new();
-    handle_matches(&matches, &config.command, &mut vars, &mut handlers);
-    if !handlers.is_empty() {
-        shell.check_handlers(&handlers);
+    if config.color_opt {
+        cmd = cmd.arg(clap::Arg::new(COLOR_ARG)
+            .long("color")
+            .value_name("WHEN")
+            .value_parser(clap::value_parser!(clap::ColorChoice))
+            .help("when to use colors")
+            .global(true));
+    }
+
+    if let Some(prefix) = &config.external_subcommands {
+        cmd = cmd.allow_external_subcommands(true)
+            .external_subcommand_value_parser(clap::value_parser!(OsString));
+        let discovered = if discover { external::discover(prefix, &config.plugin_dirs) } else { vec![] };
+        for name in discovered {
+            if cmd.find_subcommand(&name).is_none() {
+                cmd = cmd.subcommand(clap::Command::new(name)
+                    .about("external command")
+                    .disable_help_flag(true)
+                    .disable_version_flag(true)
+                    .arg(clap::Arg::new(EXTERNAL_ARGS)
+                        .num_args(0..)
+                        .value_parser(clap::value_parser!(OsString))
+                        .trailing_var_arg(true)
+                        .allow_hyphen_values(true)));
+            }
+        }
+    }
+
+    if config.multicall {
+        // symlinks to the script select the subcommand by their name,
+        // calling the script by any other name shows the subcommands.
+        let external = config.external_subcommands.as_ref()
+            .is_some_and(|prefix| external::find(prefix, progname, &config.plugin_dirs).is_some());
+        let applet = cmd.find_subcommand(progname).is_some() || external;
+        cmd = cmd.multicall(applet);
+    }
+
+    cmd
+}
+
+/// Whether the matches select an external subcommand that does not exist,
+/// with `infer-subcommands` it may be the abbreviation of a discovered one.
+fn missing_external(matches: &ArgMatches, config: &config::App, prefix: &str) -> bool {
+    match matches.subcommand() {
+        Some((name, _)) if !config.command.subcommands.iter().any(|cmd| cmd.name == name) => {
+            external::find(prefix, name, &config.plugin_dirs).is_none()
+        }
+        _ => false,
+    }
+}
+
+fn do_things<'a>(shell: &dyn Shell, config: &config::App, progname: Option<String>, args: impl Iterator<Item=&'a OsString>) -> Result<(), Error> {
+    let mut progname = progname.or(config.name.clone()).or(config.function.clone()).expect("missing program name, supply in config or via command line");
+
+    if config.multicall {
+        if let Some(basename) = std::path::Path::new(&progname).file_name() {
+            progname = basename.to_string_lossy().to_string();
+        }
+    }
+
+    let args: Vec<OsString> = std::iter::once(OsString::from(&progname)).chain(args.cloned()).collect();
+
+    // searching for external subcommands reads every dir in `PATH`,
+    // so they are only discovered when parsing fails or help is shown
+    let mut cmd = build_app(config, &progname, false);
+    let mut result = cmd.clone().try_get_matches_from(&args);
+    if let Some(prefix) = &config.external_subcommands {
+        if result.as_ref().map_or(true, |matches| missing_external(matches, config, prefix)) {
+            cmd = build_app(config, &progname, true);
+            result = cmd.clone().try_get_matches_from(&args);
+        }
     }
-    shell.set_vars(&vars);
-    shell.call_handlers(&handlers);
+    let matches = result?;
+
+    let mut vars: Vars = IndexMap::new();
+    let mut handlers: Vec<Handler> = Vec::new();
+    if config.declare_all {
+        declare_vars(&config.command, &mut vars);
+    }
+    let mut path: Vec<String> = Vec::new();
+    let mut external: Option<(String, Vec<OsString>)> = None;
+    handle_matches(&matches, &config.command, &mut vars, &mut handlers, &mut path, &mut external);
+    vars.insert(config.subcommand_var.clone(), VarValue::Val(path.join(" ").into()));
+    vars.insert(config.subcommand_path_var.clone(), VarValue::List(path.into_iter().map(OsString::from).collect()));
+    for (name, value) in &vars {
+        if let VarValue::List(values) = value {
+            if let Err(message) = shell.check_list(name, values, &config.posix_list_style) {
+                return Err(cmd.error(clap::error::ErrorKind::ValueValidation, message).into());
+            }
+        }
+    }
+    let functions = required_functions(&handlers, config);
+    let executables: Vec<String> = handlers.iter().filter(|handler| handler.execs(config)).map(|handler| handler.name.clone()).collect();
+    if let (Some((name, args)), Some(prefix)) = (external, &config.external_subcommands) {
+        let Some(executable) = external::find(prefix, &name, &config.plugin_dirs) else {
+            return Err(cmd.error(clap::error::ErrorKind::InvalidSubcommand, format!("unrecognized subcommand '{name}'")).into());
+        };
+        handlers.push(Handler {
+            // the name is passed to the error hook
+            name: shell.quote_string(name.as_ref()).into_string().expect("quoting keeps strings valid UTF-8"),
+            executable: Some(executable),
+            args,
+            // the app level after hook could not run after `exec`
+            exec: config.hooks.after.is_none(),
+            hooks: config::Hooks::default(),
+        });
+    }
+    shell.declare_locals(&vars, config);
+    if !functions.is_empty() || !executables.is_empty() {
+        shell.check_handlers(&functions, &executables, config);
+    }
+    shell.set_vars(&vars, config);
+    shell.call_handlers(&handlers, config);
 
     Ok(())
 }
@@ -221,9 +656,22 @@ fn main() {
     let matches = clap::command!()
         .arg(
             clap::arg!(
-                -c --config <CONFIG> "config string, stdin is used if not provided"
+                -c --config <CONFIG> "config string, stdin is used if neither this nor a config file is provided"
             ).global(true)
         )
+        .arg(
+            clap::arg!(
+                -f --"config-file" <PATH> "config file, included files are relative to it"
+            ).global(true)
+                .value_parser(clap::value_parser!(PathBuf))
+                .conflicts_with("config")
+        )
+        .arg(
+            clap::arg!(
+                --"config-format" <FORMAT> "format of the config, detected from the extension of the config file by default"
+            ).global(true)
+                .value_parser(["kdl", "json", "yaml", "toml"])
+        )
         .arg(
             clap::arg!(
                 -n --progname <NAME> "the name of the program to use in error messages and help text"
@@ -234,38 +682,68 @@ fn main() {
                 -N --"progname-in-args" "the first argument is used as the program name"
             ).global(true).conflicts_with("progname")
         )
+        .arg(
+            clap::arg!(
+                -s --spec <NAME> "the app block to use, for configs that contain several"
+            ).global(true)
+        )
         .subcommand(
             clap::Command::new("bash")
                 .alias("zsh")
                 .about("generate bash/zsh-compatible code")
                 .arg(clap::arg!([args] ... "arguments to parse")
+                    .value_parser(clap::value_parser!(OsString))
                     .trailing_var_arg(true))
         )
         .subcommand(
             clap::Command::new("posix")
                 .about("generate posix-compatible code")
                 .arg(clap::arg!([args] ... "arguments to parse")
+                    .value_parser(clap::value_parser!(OsString))
                     .trailing_var_arg(true))
         )
+        .subcommand(
+            clap::Command::new("schema")
+                .about("print the JSON Schema of configs")
+                .arg(clap::arg!(--kdl "print a KDL Schema Language document instead"))
+        )
+        .subcommand(
+            clap::Command::new("fmt")
+                .about("format a KDL config, the config file is rewritten if one is provided")
+                .arg(clap::arg!(--check "only check that the config is formatted, exits with 1 if not"))
+        )
         .subcommand_required(true)
         .get_matches();
 
 
     let (cmd, matches) = matches.subcommand().unwrap();
 
-    let config = matches.get_one::<String>("config");
+    if cmd == "schema" {
+        if matches.get_flag("kdl") {
+            print!("{}", schema::kdl_schema());
+        } else {
+            println!("{}", schema::json_schema());
+        }
+        return;
+    }
 
-    let mut args = matches.get_many::<String>("args")
+    if cmd == "fmt" {
+        if let Err(err) = format_config(matches) {
+            eprintln!("{err:?}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let mut args = matches.get_many::<OsString>("args")
         .unwrap_or(clap::parser::ValuesRef::default());
 
     let progname_in_args = matches.get_flag("progname-in-args");
 
     let progname = if progname_in_args {
-        Some(args.next().expect("missing program name in arguments").to_string())
-    } else if let Some(progname) = matches.get_one::<String>("progname") {
-        Some(progname.to_string())
+        Some(args.next().expect("missing program name in arguments").to_string_lossy().to_string())
     } else {
-        None
+        matches.get_one::<String>("progname").map(|progname| progname.to_string())
     };
 
 
@@ -278,10 +756,35 @@ fn main() {
     };
 
 
-    match do_things(&shell, config, progname, args) {
+    let mut config = match load_config(matches) {
+        Ok(config) => config,
+        Err(err) => {
+            shell.print_error(err, &config::App::default());
+            return;
+        }
+    };
+
+    if config.color_opt {
+        if let Some(color) = color_from_args(args.clone()) {
+            config.color = color;
+        }
+    }
+
+    match &config.wrap {
+        Wrap::None => {}
+        Wrap::Block => println!("{{"),
+        Wrap::Function(name) => println!("{name} () {{"),
+    }
+
+    match do_things(shell.as_ref(), &config, progname, args) {
         Ok(_) => {}
         Err(err) => {
-            shell.print_error(err);
+            shell.print_error(err, &config);
         }
     }
+
+    match &config.wrap {
+        Wrap::None => {}
+        Wrap::Block | Wrap::Function(_) => println!("}}"),
+    }
 }
@@ -0,0 +1,222 @@
+use std::fmt::Write;
+use serde_json::{Map, Value};
+use crate::config;
+
+/// Config structs whose fields are written as properties in KDL,
+/// the fields of all others are child nodes.
+const PROPERTY_NODES: [&str; 4] = ["CfgOpt", "CfgFlag", "CfgArg", "CfgExample"];
+
+/// The field of a config struct that is the argument of its node.
+fn argument_field(def: &str) -> Option<&'static str> {
+    match def {
+        "CfgOpt" | "CfgFlag" | "CfgArg" | "CfgCommand" => Some("name"),
+        "CfgExample" => Some("command"),
+        _ => None,
+    }
+}
+
+/// The node name of the items of a list field, list fields without one are
+/// a single node with several arguments.
+fn item_node(field: &str) -> Option<&'static str> {
+    match field {
+        "aliases" => Some("alias"),
+        "visible-aliases" => Some("visible-alias"),
+        "short-aliases" => Some("short-alias"),
+        "plugin-dirs" => Some("plugin-dir"),
+        "subcommands" => Some("subcommand"),
+        "opts" => Some("opt"),
+        "flags" => Some("flag"),
+        "args" => Some("arg"),
+        "examples" => Some("example"),
+        _ => None,
+    }
+}
+
+fn quote(s: &str) -> String {
+    format!("{:?}", s)
+}
+
+/// The `description` property of a node, if the schema has one.
+fn description(schema: &Value) -> String {
+    match schema.get("description").and_then(|x| x.as_str()) {
+        Some(description) => format!(" description={}", quote(description)),
+        None => String::new(),
+    }
+}
+
+/// The name of the definition a schema refers to, also through `Option`.
+fn reference(schema: &Value) -> Option<&str> {
+    if let Some(reference) = schema.get("$ref").and_then(|x| x.as_str()) {
+        return reference.strip_prefix("#/$defs/");
+    }
+    schema.get("anyOf")?.as_array()?.iter().find_map(reference)
+}
+
+fn is_array(schema: &Value) -> bool {
+    match schema.get("type") {
+        Some(Value::String(t)) => t == "array",
+        Some(Value::Array(types)) => types.iter().any(|t| t == "array"),
+        _ => false,
+    }
+}
+
+/// The KDL schema type and allowed values of a scalar.
+fn scalar(schema: &Value) -> String {
+    let types: Vec<&str> = match schema.get("type") {
+        Some(Value::String(t)) => vec![t.as_str()],
+        Some(Value::Array(types)) => types.iter().filter_map(|t| t.as_str()).collect(),
+        _ => vec![],
+    };
+    let kdl_type = match (types.iter().find(|t| **t != "null"), schema.get("format").and_then(|x| x.as_str())) {
+        (Some(&"integer"), Some("int32")) => "i32",
+        (Some(&"integer"), Some("uint")) => "usize",
+        (Some(&"integer"), _) => "number",
+        (Some(t), _) => t,
+        (None, _) => "string",
+    };
+    let mut scalar = format!("type {}", quote(kdl_type));
+    if let Some(Value::Array(values)) = schema.get("enum") {
+        scalar.push_str("; enum");
+        for value in values.iter().filter_map(|x| x.as_str()) {
+            write!(scalar, " {}", quote(value)).unwrap();
+        }
+    }
+    scalar
+}
+
+struct KdlSchema<'a> {
+    defs: &'a Map<String, Value>,
+    out: String,
+}
+
+impl KdlSchema<'_> {
+    fn line(&mut self, depth: usize, line: &str) {
+        writeln!(self.out, "{}{line}", "    ".repeat(depth)).unwrap();
+    }
+
+    /// Writes the value, properties and children of a node described by a config struct.
+    fn node_body(&mut self, def: &str, depth: usize) {
+        let schema = &self.defs[def];
+        self.fields(Some(def), schema, depth, true);
+    }
+
+    /// Writes the fields of a config struct, the child nodes are only wrapped
+    /// in `children` if `nested`, as the document holds them directly.
+    fn fields(&mut self, def: Option<&str>, schema: &Value, depth: usize, nested: bool) {
+        let properties = schema["properties"].as_object().unwrap();
+        let argument = def.and_then(argument_field);
+        let property_node = def.is_some_and(|def| PROPERTY_NODES.contains(&def));
+
+        if let Some(argument) = argument {
+            let value = scalar(&properties[argument]);
+            self.line(depth, &format!("value {{ {value}; min 1; max 1; }}"));
+        }
+        let mut children = Vec::new();
+        for (field, field_schema) in properties {
+            if Some(field.as_str()) == argument {
+                continue;
+            }
+            if property_node && !is_array(field_schema) && reference(field_schema).is_none() {
+                self.line(depth, &format!("prop {}{} {{ {}; }}", quote(field), description(field_schema), scalar(field_schema)));
+            } else {
+                children.push((field, field_schema));
+            }
+        }
+        if !nested {
+            for (field, field_schema) in children {
+                self.child(field, field_schema, depth);
+            }
+        } else if !children.is_empty() {
+            self.line(depth, "children {");
+            for (field, field_schema) in children {
+                self.child(field, field_schema, depth + 1);
+            }
+            self.line(depth, "}");
+        }
+    }
+
+    fn child(&mut self, field: &str, schema: &Value, depth: usize) {
+        let description = description(schema);
+        if let Some(def) = reference(schema) {
+            self.line(depth, &format!("node {}{description} {{", quote(field)));
+            self.line(depth + 1, "max 1");
+            self.node_body(def, depth + 1);
+            self.line(depth, "}");
+        } else if is_array(schema) {
+            let items = &schema["items"];
+            match (item_node(field), reference(items)) {
+                (Some(item), Some(_)) if field == "examples" => {
+                    self.line(depth, &format!("node {}{description} {{", quote(field)));
+                    self.line(depth + 1, "max 1");
+                    self.line(depth + 1, "children {");
+                    self.line(depth + 2, &format!("node {} ref={}", quote(item), quote(&format!(r#"[id="{item}"]"#))));
+                    self.line(depth + 1, "}");
+                    self.line(depth, "}");
+                }
+                (Some(item), Some(_)) => {
+                    self.line(depth, &format!("node {}{description} ref={}", quote(item), quote(&format!(r#"[id="{item}"]"#))));
+                }
+                (Some(item), None) => {
+                    self.line(depth, &format!("node {}{description} {{ value {{ {}; min 1; max 1; }}; }}", quote(item), scalar(items)));
+                }
+                (None, _) => {
+                    self.line(depth, &format!("node {}{description} {{ max 1; value {{ {}; }}; }}", quote(field), scalar(items)));
+                }
+            }
+        } else {
+            self.line(depth, &format!("node {}{description} {{ max 1; value {{ {}; min 1; max 1; }}; }}", quote(field), scalar(schema)));
+        }
+    }
+}
+
+/// A KDL Schema Language document for KDL configs, generated from the JSON Schema.
+pub fn kdl_schema() -> String {
+    let json = config::json_schema();
+    let mut schema = KdlSchema {
+        defs: json["$defs"].as_object().unwrap(),
+        out: String::new(),
+    };
+
+    schema.line(0, "document {");
+    schema.line(1, "info {");
+    schema.line(2, &format!("title {}", quote(json["title"].as_str().unwrap())));
+    schema.line(1, "}");
+
+    schema.fields(None, &json, 1, false);
+    schema.line(1, r#"node "app" description="a named spec, selected with --spec" {"#);
+    schema.line(2, r#"value { type "string"; min 1; max 1; }"#);
+    schema.fields(None, &json, 2, true);
+    schema.line(1, "}");
+    schema.line(1, r#"node "include" description="replaced by the nodes of a file" { value { type "string"; min 1; max 1; }; }"#);
+    schema.line(1, r#"node "template" description="nodes that can be used at any level" { value { type "string"; min 1; max 1; }; }"#);
+    schema.line(1, r#"node "use" description="replaced by the nodes of a template" { value { type "string"; min 1; max 1; }; }"#);
+
+    schema.line(1, "definitions {");
+    for (def, item) in [("CfgCommand", "subcommand"), ("CfgOpt", "opt"), ("CfgFlag", "flag"), ("CfgArg", "arg"), ("CfgExample", "example")] {
+        schema.line(2, &format!("node {} id={}{} {{", quote(item), quote(item), description(&json["$defs"][def])));
+        schema.node_body(def, 3);
+        schema.line(2, "}");
+    }
+    schema.line(1, "}");
+    schema.line(0, "}");
+
+    schema.out
+}
+
+/// The JSON Schema of configs, pretty printed.
+pub fn json_schema() -> String {
+    serde_json::to_string_pretty(&config::json_schema()).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn committed_schemas_are_up_to_date() {
+        assert!(format!("{}\n", json_schema()) == include_str!("../schema/config.schema.json"),
+            "regenerate with `clap-sh schema > schema/config.schema.json`");
+        assert!(kdl_schema() == include_str!("../schema/config.kdl-schema.kdl"),
+            "regenerate with `clap-sh schema --kdl > schema/config.kdl-schema.kdl`");
+    }
+}
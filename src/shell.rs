@@ -1,110 +1,341 @@
-use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
 use std::fmt::Write;
+use std::io::Write as _;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use clap::ColorChoice;
+use crate::config::{App, Hooks, ListStyle, UnsetStyle};
 use crate::error::Error;
-use crate::VarValue;
+use crate::{Handler, VarValue, Vars};
 
 pub trait Shell {
-    fn quote_string(&self, s: &str) -> String;
-    fn set_string_var(&self, name: &str, value: &str);
-    fn set_list_var(&self, name: &str, value: &Vec<String>);
-    fn call_handler(&self, name: &str);
+    /// Quotes a string byte-exact, it does not need to be valid UTF-8.
+    fn quote_string(&self, s: &OsStr) -> OsString;
+    fn set_string_var(&self, name: &str, value: &OsStr);
+    /// Sets a list variable, `style` only applies to shells without arrays.
+    fn set_list_var(&self, name: &str, value: &[OsString], style: &ListStyle);
+    /// Checks that the values of a list can be represented in `style`.
+    fn check_list(&self, _name: &str, _values: &[OsString], _style: &ListStyle) -> Result<(), String> {
+        Ok(())
+    }
+    fn unset_var(&self, name: &str);
+
+    /// The call of a handler, followed by `on_error`.
+    fn handler_call(&self, handler: &Handler, on_error: &str, config: &App) -> OsString {
+        let mut call = OsString::from(if handler.execs(config) { "exec " } else { "" });
+        match &handler.executable {
+            Some(executable) => call.push(self.quote_string(executable.as_os_str())),
+            None => call.push(&handler.name),
+        }
+        for arg in &handler.args {
+            call.push(" ");
+            call.push(self.quote_string(arg));
+        }
+        call.push(on_error);
+        call
+    }
+
+    /// Defines `__argparse_color`, which decides if output to the file descriptor
+    /// given as its argument is styled.
+    fn define_color_check(&self, color: ColorChoice);
+
+    fn print_error(&self, err: Error, config: &App);
+
+    /// Checks that the handlers and hooks to call exist, handlers that are
+    /// called with `exec` must be executables, as functions can not be exec'd.
+    fn check_handlers(&self, functions: &[String], executables: &[String], config: &App);
+
+    /// Makes the variables local to `function`, before they are set.
+    fn local_vars(&self, names: &[String], function: &str);
+
+    /// Leaves the script, or only the function in function mode.
+    fn exit(&self, config: &App, status: &str) -> String {
+        match config.function {
+            None => format!("exit {status}"),
+            Some(_) => format!("return {status}"),
+        }
+    }
+
+    /// The code run when the handler or hook `name` fails: the error hook is
+    /// called with the status and the name, followed by the `cleanup` hooks.
+    fn on_error(&self, name: &str, error_hook: Option<&String>, cleanup: &[&String], config: &App) -> String {
+        if error_hook.is_none() && cleanup.is_empty() {
+            return format!(" || {}", self.exit(config, "$?"));
+        }
+        let mut code = String::from(" || { __argparse_status=$?; ");
+        if let Some(hook) = error_hook {
+            write!(code, r#"{hook} "$__argparse_status" {name}; "#).unwrap();
+        }
+        for hook in cleanup {
+            write!(code, "{hook}; ").unwrap();
+        }
+        write!(code, "{}; }}", self.exit(config, r#""$__argparse_status""#)).unwrap();
+        code
+    }
 
-    fn print_error(&self, err: Error);
+    /// The names of the variables a list is stored in.
+    fn list_var_names(&self, name: &str, len: usize, style: &ListStyle) -> Vec<String>;
 
-    fn check_handlers(&self, handlers: &Vec<String>);
+    /// Defines the functions needed to use lists in the given style.
+    fn define_list_helpers(&self, _style: &ListStyle) {}
 
-    fn call_handlers(&self, handlers: &Vec<String>) {
+    /// The calls of the handlers and their hooks. Without hooks the handlers are
+    /// called as they are, so that they keep `set -e`. With hooks a failing hook
+    /// stops the chain like a failing handler, the after hooks whose before hooks
+    /// ran are still called. The calls are then followed by `||`, which disables
+    /// `set -e` inside the handlers and hooks.
+    fn handler_calls(&self, handlers: &[Handler], config: &App) -> Vec<OsString> {
+        let hooks = &config.hooks;
+        let has_hooks = |hooks: &Hooks| hooks.before.is_some() || hooks.after.is_some() || hooks.error.is_some();
+        if !has_hooks(hooks) && !handlers.iter().any(|handler| has_hooks(&handler.hooks)) {
+            return handlers.iter().map(|handler| self.handler_call(handler, "", config)).collect();
+        }
+        let mut calls = Vec::new();
+        if let Some(before) = &hooks.before {
+            calls.push(format!("{before}{}", self.on_error(before, hooks.error.as_ref(), &[], config)).into());
+        }
+        let app_cleanup: Vec<&String> = hooks.after.iter().collect();
         for handler in handlers {
-            self.call_handler(handler);
+            let error_hook = handler.hooks.error.as_ref().or(hooks.error.as_ref());
+            if let Some(before) = &handler.hooks.before {
+                calls.push(format!("{before}{}", self.on_error(before, error_hook, &app_cleanup, config)).into());
+            }
+            let cleanup: Vec<&String> = handler.hooks.after.iter().chain(app_cleanup.iter().copied()).collect();
+            let on_error = self.on_error(&handler.name, error_hook, &cleanup, config);
+            calls.push(self.handler_call(handler, &on_error, config));
+            if let Some(after) = &handler.hooks.after {
+                calls.push(format!("{after}{}", self.on_error(after, error_hook, &app_cleanup, config)).into());
+            }
+        }
+        if let Some(after) = &hooks.after {
+            calls.push(format!("{after}{}", self.on_error(after, hooks.error.as_ref(), &[], config)).into());
+        }
+        calls
+    }
+
+    fn call_handlers(&self, handlers: &[Handler], config: &App) {
+        for call in self.handler_calls(handlers, config) {
+            print_line(&[&call]);
         }
     }
 
-    fn set_vars(&self, vars: &HashMap<String, VarValue>) {
+    /// Makes all variables that are set local in function mode,
+    /// before anything can leave the function.
+    fn declare_locals(&self, vars: &Vars, config: &App) {
+        if let Some(function) = &config.function {
+            let mut names = vec!["__argparse_status".to_string()];
+            for (name, value) in vars {
+                match value {
+                    VarValue::Unset => match &config.unset_style {
+                        UnsetStyle::Marker(suffix) => {
+                            names.push(name.clone());
+                            names.push(format!("{name}{suffix}"));
+                        }
+                        _ => names.push(name.clone()),
+                    },
+                    VarValue::Val(_) => names.push(name.clone()),
+                    VarValue::List(vs) => names.extend(self.list_var_names(name, vs.len(), &config.posix_list_style)),
+                }
+            }
+            self.local_vars(&names, function);
+        }
+    }
+
+    fn set_vars(&self, vars: &Vars, config: &App) {
+        self.define_list_helpers(&config.posix_list_style);
         for (name, value) in vars {
             match value {
-                VarValue::Unset => {
-                    self.set_string_var(format!("{name}_not_set").as_str(), "y");
-                }
+                VarValue::Unset => match &config.unset_style {
+                    UnsetStyle::Marker(suffix) => {
+                        if config.declare_all {
+                            self.set_string_var(name.as_str(), OsStr::new(""));
+                        }
+                        self.set_string_var(format!("{name}{suffix}").as_str(), OsStr::new("y"));
+                    }
+                    UnsetStyle::Unset => {
+                        self.unset_var(name.as_str());
+                    }
+                    UnsetStyle::Empty => {
+                        self.set_string_var(name.as_str(), OsStr::new(""));
+                    }
+                    UnsetStyle::Sentinel(sentinel) => {
+                        self.set_string_var(name.as_str(), OsStr::new(sentinel));
+                    }
+                },
                 VarValue::Val(v) => {
-                    self.set_string_var(name.as_str(), v.as_str());
+                    self.set_string_var(name.as_str(), v);
                 }
                 VarValue::List(vs) => {
-                    self.set_list_var(name.as_str(), vs);
+                    self.set_list_var(name.as_str(), vs, &config.posix_list_style);
                 }
             }
         }
     }
 }
 
+/// The variables `print_error` sets.
+const ERROR_VARS: [&str; 3] = ["__argparse_error_unstyled", "__argparse_error_styled", "__argparse_error_kind"];
+
+/// Prints a line of generated code that can contain arbitrary bytes.
+fn print_line(parts: &[&OsStr]) {
+    let mut stdout = std::io::stdout().lock();
+    for part in parts {
+        stdout.write_all(part.as_bytes()).unwrap();
+    }
+    stdout.write_all(b"\n").unwrap();
+}
+
+/// Joins quoted strings with a space in between.
+fn join_quoted(quoted: impl Iterator<Item=OsString>) -> OsString {
+    let mut joined = OsString::new();
+    for (i, x) in quoted.enumerate() {
+        if i > 0 {
+            joined.push(" ");
+        }
+        joined.push(x);
+    }
+    joined
+}
+
+/// Indents every line of a snippet of generated code by two spaces.
+fn indent(code: &str) -> String {
+    code.lines().map(|line| format!("  {line}")).collect::<Vec<_>>().join("\n")
+}
+
 pub struct BashZsh {}
 
 impl Shell for BashZsh {
-    fn quote_string(&self, s: &str) -> String {
-        let escaped = s
-            .replace("\\", r#"\\"#)
-            .replace("'", r#"\'"#)
-            .replace("\r", r#"\r"#)
-            .replace("\n", r#"\n"#)
-            .replace("\t", r#"\t"#)
-            .replace("\x1b", r#"\e"#)
-            ;
-
-        if s.len() != escaped.len() {
-            format!("$'{}'", escaped)
+    fn quote_string(&self, s: &OsStr) -> OsString {
+        let mut escaped = String::new();
+        let mut special = false;
+        for chunk in s.as_bytes().utf8_chunks() {
+            for c in chunk.valid().chars() {
+                match c {
+                    '\\' => escaped.push_str(r#"\\"#),
+                    '\'' => escaped.push_str(r#"\'"#),
+                    '\r' => escaped.push_str(r#"\r"#),
+                    '\n' => escaped.push_str(r#"\n"#),
+                    '\t' => escaped.push_str(r#"\t"#),
+                    '\x1b' => escaped.push_str(r#"\e"#),
+                    c => {
+                        escaped.push(c);
+                        continue;
+                    }
+                }
+                special = true;
+            }
+            for byte in chunk.invalid() {
+                write!(escaped, r#"\x{byte:02x}"#).unwrap();
+                special = true;
+            }
+        }
+
+        if special {
+            format!("$'{}'", escaped).into()
         } else {
-            format!("'{}'", escaped)
+            format!("'{}'", escaped).into()
         }
     }
-    fn set_string_var(&self, name: &str, value: &str) {
-        println!("{name}={v}", v = self.quote_string(value));
+    fn set_string_var(&self, name: &str, value: &OsStr) {
+        print_line(&[format!("{name}=").as_ref(), &self.quote_string(value)]);
     }
 
-    fn set_list_var(&self, name: &str, values: &Vec<String>) {
-        println!("{name}=({values})", values = values.iter().map(|x|
-            self.quote_string(x)
-        ).collect::<Vec<_>>().join(" "));
+    fn set_list_var(&self, name: &str, values: &[OsString], _style: &ListStyle) {
+        let values = join_quoted(values.iter().map(|x| self.quote_string(x)));
+        print_line(&[format!("{name}=(").as_ref(), &values, OsStr::new(")")]);
     }
 
-    fn call_handler(&self, name: &str) {
-        println!("{name}");
+    fn unset_var(&self, name: &str) {
+        println!("unset {name}");
     }
 
-    fn print_error(&self, err: Error) {
+    fn local_vars(&self, names: &[String], _function: &str) {
+        if !names.is_empty() {
+            println!("local {}", names.join(" "));
+        }
+    }
+
+    fn list_var_names(&self, name: &str, _len: usize, _style: &ListStyle) -> Vec<String> {
+        vec![name.to_string()]
+    }
+
+    fn define_color_check(&self, color: ColorChoice) {
+        print!(r#"
+__argparse_color () {{
+  case {color} in
+    always) return 0 ;;
+    never) return 1 ;;
+  esac
+  if [[ -n "${{NO_COLOR:-}}" ]]; then
+    return 1
+  fi
+  if [[ -n "${{CLICOLOR_FORCE:-}}" && "${{CLICOLOR_FORCE}}" != 0 ]]; then
+    return 0
+  fi
+  [[ -t "$1" ]]
+}}
+"#);
+    }
+
+    fn print_error(&self, err: Error, config: &App) {
         let message_unstyled = format!("{}", err.message());
         let message_styled = format!("{}", err.message().ansi());
 
-        self.set_string_var("__argparse_error_unstyled", message_unstyled.as_str());
-        self.set_string_var("__argparse_error_styled", message_styled.as_str());
+        if let Some(function) = &config.function {
+            self.local_vars(&ERROR_VARS.map(String::from), function);
+        }
+        self.set_string_var("__argparse_error_unstyled", message_unstyled.as_ref());
+        self.set_string_var("__argparse_error_styled", message_styled.as_ref());
+        self.set_string_var("__argparse_error_kind", err.kind_name().as_ref());
+        self.define_color_check(config.color);
 
-        print!(r#"
-if [[ -t 1 ]]; then
+        let print = format!(r#"if __argparse_color {fd}; then
   {redirect} echo "${{__argparse_error_styled}}"
 else
   {redirect} echo "${{__argparse_error_unstyled}}"
-fi
-exit 1
-"#, redirect = if err.use_stderr() { ">&2" } else { "" });
+fi"#,
+            redirect = if err.use_stderr() { ">&2" } else { "" },
+            fd = if err.use_stderr() { 2 } else { 1 });
+
+        let exit = self.exit(config, err.exit_code(&config.exit_codes).to_string().as_str());
+        // help and version output is not an error and never goes to the error function,
+        // which decides with its status if the script exits
+        match config.on_parse_error.as_ref().filter(|_| err.use_stderr()) {
+            None => println!("\n{print}\n{exit}"),
+            Some(func) => println!(r#"
+if type -t {func} >/dev/null; then
+  {func} "${{__argparse_error_unstyled}}" "${{__argparse_error_styled}}" "${{__argparse_error_kind}}" || {status_exit}
+else
+{print}
+  {exit}
+fi"#, print = indent(print.as_str()), status_exit = self.exit(config, "$?")),
+        }
     }
 
-    fn check_handlers(&self, handlers: &Vec<String>) {
+    fn check_handlers(&self, functions: &[String], executables: &[String], config: &App) {
         let mut err_message = clap::builder::StyledStr::new();
-        err_message.write_str(color_print::cstr!("<bold><red>error:</red></bold> handler <yellow>'%s'</yellow> not found\n")).unwrap();
+        err_message.write_str(color_print::cstr!("<bold><red>error:</red></bold> handler <yellow>'%s'</yellow> %s\n")).unwrap();
 
         let err_unstyled = err_message.to_string();
         let err_styled = err_message.ansi().to_string();
 
+        self.define_color_check(config.color);
         print!(r#"
 __argparse_handler_err () {{
-  if [[ -t 1 ]]; then
-    >&2 printf {err_styled} "$1"
+  if __argparse_color 2; then
+    >&2 printf {err_styled} "$1" "$2"
   else
-    >&2 printf {err_unstyled} "$1"
+    >&2 printf {err_unstyled} "$1" "$2"
   fi
-  exit 1
 }}
-"#, err_styled = self.quote_string(err_styled.as_str()), err_unstyled = self.quote_string(err_unstyled.as_str()));
-        for handler in handlers {
-            println!("type -t {handler} >/dev/null || __argparse_handler_err {handler}");
+"#,
+            err_styled = self.quote_string(err_styled.as_ref()).to_string_lossy(),
+            err_unstyled = self.quote_string(err_unstyled.as_ref()).to_string_lossy());
+        for function in functions {
+            println!("type -t {function} >/dev/null || {{ __argparse_handler_err {function} 'not found'; {}; }}", self.exit(config, "1"));
+        }
+        // `exec` only runs executables, bash finds them with `type -P`, zsh with `whence -p`
+        for executable in executables {
+            println!("{{ type -P {executable} || whence -p {executable}; }} >/dev/null 2>&1 || {{ __argparse_handler_err {executable} 'is not an executable (required by exec-handler)'; {}; }}", self.exit(config, "1"));
         }
     }
 }
@@ -112,64 +343,284 @@ __argparse_handler_err () {{
 pub struct Posix {}
 
 impl Shell for Posix {
-    fn quote_string(&self, s: &str) -> String {
-        let escaped = s.replace("'", r#"'\''"#);
-        let quoted = format!("'{}'", escaped);
-        let quoted = quoted.strip_prefix("''").unwrap_or(quoted.as_str());
-        let quoted = quoted.strip_suffix("''").unwrap_or(quoted);
+    fn quote_string(&self, s: &OsStr) -> OsString {
+        let mut quoted = vec![b'\''];
+        for &byte in s.as_bytes() {
+            if byte == b'\'' {
+                quoted.extend_from_slice(br#"'\''"#);
+            } else {
+                quoted.push(byte);
+            }
+        }
+        quoted.push(b'\'');
+        let quoted = quoted.strip_prefix(b"''").unwrap_or(quoted.as_slice());
+        let quoted = quoted.strip_suffix(b"''").unwrap_or(quoted);
+        if quoted.is_empty() {
+            return "''".into();
+        }
 
-        quoted.to_string()
+        OsString::from_vec(quoted.to_vec())
     }
-    fn set_string_var(&self, name: &str, value: &str) {
-        println!("{name}={v}", v = self.quote_string(value));
+    fn set_string_var(&self, name: &str, value: &OsStr) {
+        print_line(&[format!("{name}=").as_ref(), &self.quote_string(value)]);
     }
 
-    fn set_list_var(&self, name: &str, values: &Vec<String>) {
-        println!("{name}={values}", values = self.quote_string(values.iter().map(|x|
-            self.quote_string(x)
-        ).collect::<Vec<_>>().join(" ").as_str()));
+    fn set_list_var(&self, name: &str, values: &[OsString], style: &ListStyle) {
+        match style {
+            ListStyle::Quoted => {
+                let values = join_quoted(values.iter().map(|x| self.quote_string(x)));
+                self.set_string_var(name, &values);
+            }
+            ListStyle::Set => {
+                let mut set = OsString::from("set --");
+                for value in values {
+                    set.push(" ");
+                    set.push(self.quote_string(value));
+                }
+                self.set_string_var(name, &set);
+            }
+            ListStyle::Numbered => {
+                self.set_string_var(format!("{name}_count").as_str(), values.len().to_string().as_ref());
+                for (i, value) in values.iter().enumerate() {
+                    self.set_string_var(format!("{name}_{}", i + 1).as_str(), value);
+                }
+            }
+            ListStyle::Lines => {
+                let mut lines = Vec::new();
+                for value in values {
+                    lines.extend_from_slice(value.as_bytes());
+                    lines.push(b'\n');
+                }
+                self.set_string_var(name, &OsString::from_vec(lines));
+            }
+        }
     }
 
-    fn call_handler(&self, name: &str) {
-        println!("{name}");
+    fn check_list(&self, name: &str, values: &[OsString], style: &ListStyle) -> Result<(), String> {
+        if let ListStyle::Lines = style {
+            if values.iter().any(|value| value.as_bytes().contains(&b'\n')) {
+                return Err(format!("a value of '{name}' contains a newline, which the lines list style can not represent"));
+            }
+        }
+        Ok(())
+    }
+
+    fn unset_var(&self, name: &str) {
+        println!("unset {name}");
     }
 
-    fn print_error(&self, err: Error) {
+    /// Posix shells have no `local`, the previous values are saved instead and
+    /// `__argparse_restore_<function> [status]` restores them and returns the status.
+    /// The generated code calls it when it returns from the function, after a
+    /// successful parse the function has to call it itself before returning.
+    fn local_vars(&self, names: &[String], function: &str) {
+        let mut restore = String::new();
+        for name in names {
+            let saved = format!("__argparse_saved_{function}_{name}");
+            println!("{saved}=${{{name}+x${name}}}");
+            println!("unset {name}");
+            writeln!(restore, r#"  case ${saved} in x*) {name}=${{{saved}#x}} ;; *) unset {name} ;; esac"#).unwrap();
+            writeln!(restore, "  unset {saved}").unwrap();
+        }
+        print!(r#"
+__argparse_restore_{function} () {{
+{restore}  return "${{1:-0}}"
+}}
+"#);
+    }
+
+    /// In function mode the saved variables are restored before returning.
+    fn exit(&self, config: &App, status: &str) -> String {
+        match &config.function {
+            None => format!("exit {status}"),
+            Some(function) => format!("{{ __argparse_restore_{function} {status}; return; }}"),
+        }
+    }
+
+    fn list_var_names(&self, name: &str, len: usize, style: &ListStyle) -> Vec<String> {
+        match style {
+            ListStyle::Numbered => std::iter::once(format!("{name}_count"))
+                .chain((1..=len).map(|i| format!("{name}_{i}")))
+                .collect(),
+            _ => vec![name.to_string()],
+        }
+    }
+
+    fn define_color_check(&self, color: ColorChoice) {
+        print!(r#"
+__argparse_color () {{
+  case {color} in
+    always) return 0 ;;
+    never) return 1 ;;
+  esac
+  if [ -n "${{NO_COLOR:-}}" ]; then
+    return 1
+  fi
+  if [ -n "${{CLICOLOR_FORCE:-}}" ] && [ "${{CLICOLOR_FORCE}}" != 0 ]; then
+    return 0
+  fi
+  [ -t "$1" ]
+}}
+"#);
+    }
+
+    fn print_error(&self, err: Error, config: &App) {
         let message_unstyled = format!("{}", err.message());
         let message_styled = format!("{}", err.message().ansi());
 
-        self.set_string_var("__argparse_error_unstyled", message_unstyled.as_str());
-        self.set_string_var("__argparse_error_styled", message_styled.as_str());
+        if let Some(function) = &config.function {
+            self.local_vars(&ERROR_VARS.map(String::from), function);
+        }
+        self.set_string_var("__argparse_error_unstyled", message_unstyled.as_ref());
+        self.set_string_var("__argparse_error_styled", message_styled.as_ref());
+        self.set_string_var("__argparse_error_kind", err.kind_name().as_ref());
+        self.define_color_check(config.color);
 
-        print!(r#"
-if [ -t 1 ]; then
+        let print = format!(r#"if __argparse_color {fd}; then
   {redirect} printf '%s' "${{__argparse_error_styled}}"
 else
   {redirect} printf '%s' "${{__argparse_error_unstyled}}"
-fi
-exit 1
-"#, redirect = if err.use_stderr() { ">&2" } else { "" });
+fi"#,
+            redirect = if err.use_stderr() { ">&2" } else { "" },
+            fd = if err.use_stderr() { 2 } else { 1 });
+
+        let exit = self.exit(config, err.exit_code(&config.exit_codes).to_string().as_str());
+        // help and version output is not an error and never goes to the error function,
+        // which decides with its status if the script exits
+        match config.on_parse_error.as_ref().filter(|_| err.use_stderr()) {
+            None => println!("\n{print}\n{exit}"),
+            Some(func) => {
+                println!(r#"
+if type {func} >/dev/null 2>&1; then
+  {func} "${{__argparse_error_unstyled}}" "${{__argparse_error_styled}}" "${{__argparse_error_kind}}" || {status_exit}
+else
+{print}
+  {exit}
+fi"#, print = indent(print.as_str()), status_exit = self.exit(config, "$?"));
+                if let Some(function) = &config.function {
+                    println!("__argparse_restore_{function}");
+                }
+            }
+        }
     }
 
-    fn check_handlers(&self, handlers: &Vec<String>) {
+    fn check_handlers(&self, functions: &[String], executables: &[String], config: &App) {
         let mut err_message = clap::builder::StyledStr::new();
-        err_message.write_str(color_print::cstr!("<bold><red>error:</red></bold> handler <yellow>'%s'</yellow> not found\n")).unwrap();
+        err_message.write_str(color_print::cstr!("<bold><red>error:</red></bold> handler <yellow>'%s'</yellow> %s\n")).unwrap();
 
         let err_unstyled = err_message.to_string();
         let err_styled = err_message.ansi().to_string();
 
+        self.define_color_check(config.color);
         print!(r#"
 __argparse_handler_err () {{
-  if [ -t 1 ]; then
-    >&2 printf {err_styled} "$1"
+  if __argparse_color 2; then
+    >&2 printf {err_styled} "$1" "$2"
   else
-    >&2 printf {err_unstyled} "$1"
+    >&2 printf {err_unstyled} "$1" "$2"
   fi
-  exit 1
 }}
-"#, err_styled = self.quote_string(err_styled.as_str()), err_unstyled = self.quote_string(err_unstyled.as_str()));
-        for handler in handlers {
-            println!("type {handler} >/dev/null || __argparse_handler_err {handler}");
+"#,
+            err_styled = self.quote_string(err_styled.as_ref()).to_string_lossy(),
+            err_unstyled = self.quote_string(err_unstyled.as_ref()).to_string_lossy());
+        for function in functions {
+            println!("type {function} >/dev/null || {{ __argparse_handler_err {function} 'not found'; {}; }}", self.exit(config, "1"));
+        }
+        // `exec` only runs executables, `command -v` prints their path but only
+        // the name of builtins and functions, which are unset in the subshell
+        for executable in executables {
+            println!("case $(unset -f {executable}; command -v {executable}) in */*) ;; *) __argparse_handler_err {executable} 'is not an executable (required by exec-handler)'; {};; esac", self.exit(config, "1"));
+        }
+    }
+
+    fn define_list_helpers(&self, style: &ListStyle) {
+        if let ListStyle::Lines = style {
+            println!(r#"
+__argparse_foreach () {{
+  eval "__argparse_foreach_list=\${{$1}}"
+  [ -n "$__argparse_foreach_list" ] || return 0
+  while IFS= read -r __argparse_foreach_item; do
+    "$2" "$__argparse_foreach_item" || return
+  done <<__ARGPARSE_EOF__
+${{__argparse_foreach_list%?}}
+__ARGPARSE_EOF__
+}}
+"#);
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quote(shell: &dyn Shell, bytes: &[u8]) -> Vec<u8> {
+        shell.quote_string(OsStr::from_bytes(bytes)).into_vec()
+    }
+
+    #[test]
+    fn bash_quotes_plain_strings_in_single_quotes() {
+        assert_eq!(quote(&BashZsh {}, b"hello world"), b"'hello world'");
+        assert_eq!(quote(&BashZsh {}, b""), b"''");
+    }
+
+    #[test]
+    fn bash_escapes_quotes_and_invalid_utf8() {
+        assert_eq!(quote(&BashZsh {}, b"it's"), br"$'it\'s'");
+        assert_eq!(quote(&BashZsh {}, b"a\xffb\xc3"), br"$'a\xffb\xc3'");
+        assert_eq!(quote(&BashZsh {}, "ü\n".as_bytes()), "$'ü\\n'".as_bytes());
+    }
+
+    #[test]
+    fn posix_keeps_bytes_raw() {
+        assert_eq!(quote(&Posix {}, b"a\xffb\n"), b"'a\xffb\n'");
+        assert_eq!(quote(&Posix {}, b""), b"''");
+    }
+
+    #[test]
+    fn posix_escapes_quotes_outside_of_single_quotes() {
+        assert_eq!(quote(&Posix {}, b"it's"), br"'it'\''s'");
+        assert_eq!(quote(&Posix {}, b"'"), br"\'");
+        assert_eq!(quote(&Posix {}, b"'a'"), br"\''a'\'");
+    }
+
+    #[test]
+    fn lines_reject_values_with_newlines() {
+        let values = [OsString::from("a"), OsString::from("b\nc")];
+        assert!(Posix {}.check_list("x", &values, &ListStyle::Lines).is_err());
+        assert!(Posix {}.check_list("x", &values[..1], &ListStyle::Lines).is_ok());
+        assert!(Posix {}.check_list("x", &values, &ListStyle::Quoted).is_ok());
+        assert!(BashZsh {}.check_list("x", &values, &ListStyle::Lines).is_ok());
+    }
+
+    fn handler(name: &str, hooks: Hooks) -> Handler {
+        Handler { name: name.to_string(), executable: None, args: vec![OsString::from("a b")], exec: false, hooks }
+    }
+
+    fn calls(shell: &dyn Shell, handlers: &[Handler], config: &App) -> Vec<String> {
+        shell.handler_calls(handlers, config).into_iter().map(|call| call.into_string().unwrap()).collect()
+    }
+
+    #[test]
+    fn handlers_without_hooks_are_called_bare() {
+        let handlers = [handler("main", Hooks::default()), handler("sub", Hooks::default())];
+        let config = App::default();
+        assert_eq!(calls(&BashZsh {}, &handlers, &config), ["main 'a b'", "sub 'a b'"]);
+        assert_eq!(calls(&Posix {}, &handlers, &config), ["main 'a b'", "sub 'a b'"]);
+    }
+
+    #[test]
+    fn handlers_with_hooks_stop_the_chain() {
+        let hooks = Hooks { before: Some("setup".to_string()), after: Some("teardown".to_string()), error: None };
+        let handlers = [handler("main", hooks)];
+        let mut config = App::default();
+        assert_eq!(calls(&BashZsh {}, &handlers, &config), [
+            "setup || exit $?",
+            "main 'a b' || { __argparse_status=$?; teardown; exit \"$__argparse_status\"; }",
+            "teardown || exit $?",
+        ]);
+        config.hooks.error = Some("failed".to_string());
+        config.function = Some("f".to_string());
+        assert_eq!(calls(&BashZsh {}, &handlers, &config)[1],
+            "main 'a b' || { __argparse_status=$?; failed \"$__argparse_status\" main; teardown; return \"$__argparse_status\"; }");
+    }
+}